@@ -0,0 +1,217 @@
+use crate::engine::{Amount, Asset, Transaction, TransactionType};
+use crate::format::{Input, Record, Row};
+use anyhow::{anyhow, ensure, Context, Result};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use TransactionType::*;
+
+/// Identifies the format and its version at the start of a file
+const MAGIC: &[u8; 4] = b"TXB1";
+
+/// Type, flags, client, tx, counterparty, timestamp and amount, all little endian
+pub const RECORD_SIZE: usize = 1 + 1 + 2 + 4 + 2 + 8 + 16;
+
+/// Amounts are stored as integer number of ten-thousandths
+const SCALE: u32 = 4;
+
+/// Flags for the optional fields, which are zero if absent
+const HAS_AMOUNT: u8 = 1;
+const HAS_TIMESTAMP: u8 = 2;
+const HAS_COUNTERPARTY: u8 = 4;
+
+/// The columns of the rows reported as rejected
+pub const COLUMNS: [&str; 6] = ["type", "client", "tx", "amount", "timestamp", "counterparty"];
+
+/// The transaction types by their code, with the names used in the rejection report
+const TYPES: [(TransactionType, &str); 9] = [
+    (Deposit, "deposit"),
+    (Withdrawal, "withdrawal"),
+    (Dispute, "dispute"),
+    (Resolve, "resolve"),
+    (Chargeback, "chargeback"),
+    (Transfer, "transfer"),
+    (Unlock, "unlock"),
+    (Freeze, "freeze"),
+    (Close, "close"),
+];
+
+/// Writes transactions as fixed-width records, which can be read without parsing text.
+/// The records have no asset, so only transactions in the default asset can be written.
+pub struct BinaryWriter<W: Write> {
+    writer: BufWriter<W>,
+}
+
+impl<W: Write> BinaryWriter<W> {
+    pub fn new(writer: W) -> Result<Self> {
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(MAGIC)?;
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, transaction: &Transaction) -> Result<()> {
+        ensure!(
+            transaction.asset == Asset::default(),
+            "the binary format only supports the default asset"
+        );
+        let code = TYPES
+            .iter()
+            .position(|(transaction_type, _)| *transaction_type == transaction.transaction_type)
+            .context("unknown transaction type")?;
+        let mut flags = 0;
+        let amount = match transaction.amount {
+            Some(amount) => {
+                flags |= HAS_AMOUNT;
+                amount
+                    .to_scaled(SCALE)
+                    .with_context(|| format!("amount {} has more than {} decimal places", amount, SCALE))?
+            }
+            None => 0,
+        };
+        let timestamp = match transaction.timestamp {
+            Some(timestamp) => {
+                flags |= HAS_TIMESTAMP;
+                timestamp.into()
+            }
+            None => 0,
+        };
+        let counterparty = match transaction.counterparty {
+            Some(counterparty) => {
+                flags |= HAS_COUNTERPARTY;
+                counterparty.into()
+            }
+            None => 0,
+        };
+        let mut record = [0; RECORD_SIZE];
+        record[0] = code as u8;
+        record[1] = flags;
+        record[2..4].copy_from_slice(&u16::from(transaction.client).to_le_bytes());
+        record[4..8].copy_from_slice(&u32::from(transaction.tx).to_le_bytes());
+        record[8..10].copy_from_slice(&u16::to_le_bytes(counterparty));
+        record[10..18].copy_from_slice(&u64::to_le_bytes(timestamp));
+        record[18..34].copy_from_slice(&amount.to_le_bytes());
+        self.writer.write_all(&record)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the fixed-width records written by `BinaryWriter`.
+pub struct BinaryReader<R: Read> {
+    reader: BufReader<R>,
+    /// The number of the last record, which is reported as its line
+    record: u64,
+}
+
+impl<R: Read> BinaryReader<R> {
+    pub fn new(reader: R) -> Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic).context("missing binary header")?;
+        ensure!(&magic == MAGIC, "not a binary transaction file");
+        Ok(Self { reader, record: 0 })
+    }
+
+    /// Reads the next record, failing if the file ends within a record.
+    pub fn next(&mut self) -> Result<Option<Row>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let mut bytes = [0; RECORD_SIZE];
+        self.record += 1;
+        self.reader
+            .read_exact(&mut bytes)
+            .with_context(|| format!("truncated record {}", self.record))?;
+        Ok(Some(self.decode(&bytes)))
+    }
+
+    fn decode(&self, bytes: &[u8; RECORD_SIZE]) -> Row {
+        let fields = Fields::new(bytes);
+        // The record is only turned into text if it's reported
+        let record = Record::Binary(self.record, *bytes);
+        let Some(&(transaction_type, _)) = fields.transaction_type else {
+            return Row::Invalid(
+                record,
+                anyhow!("unknown transaction type {} in record {}", fields.code, self.record),
+            );
+        };
+        let amount = match fields.amount {
+            Some(None) => return Row::Invalid(record, anyhow!("amount out of range in record {}", self.record)),
+            amount => amount.flatten(),
+        };
+        let transaction = Transaction {
+            transaction_type,
+            client: fields.client.into(),
+            tx: fields.tx.into(),
+            amount,
+            timestamp: fields.timestamp.map(Into::into),
+            asset: Asset::default(),
+            counterparty: fields.counterparty.map(Into::into),
+        };
+        Row::Parsed(record, transaction)
+    }
+}
+
+/// The fields of a record, with an amount that is `Some(None)` if it's out of range.
+struct Fields {
+    code: u8,
+    transaction_type: Option<&'static (TransactionType, &'static str)>,
+    client: u16,
+    tx: u32,
+    amount: Option<Option<Amount>>,
+    timestamp: Option<u64>,
+    counterparty: Option<u16>,
+}
+
+impl Fields {
+    fn new(bytes: &[u8; RECORD_SIZE]) -> Self {
+        let flags = bytes[1];
+        let amount = i128::from_le_bytes(bytes[18..34].try_into().unwrap_or_default());
+        Self {
+            code: bytes[0],
+            transaction_type: TYPES.get(bytes[0] as usize),
+            client: u16::from_le_bytes([bytes[2], bytes[3]]),
+            tx: u32::from_le_bytes(bytes[4..8].try_into().unwrap_or_default()),
+            amount: (flags & HAS_AMOUNT != 0).then(|| Amount::from_scaled(amount, SCALE)),
+            timestamp: (flags & HAS_TIMESTAMP != 0)
+                .then(|| u64::from_le_bytes(bytes[10..18].try_into().unwrap_or_default())),
+            counterparty: (flags & HAS_COUNTERPARTY != 0).then(|| u16::from_le_bytes([bytes[8], bytes[9]])),
+        }
+    }
+}
+
+/// The record as text, in the `COLUMNS` of the rejection report.
+pub fn text(bytes: &[u8; RECORD_SIZE]) -> csv::StringRecord {
+    let fields = Fields::new(bytes);
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    csv::StringRecord::from(vec![
+        fields
+            .transaction_type
+            .map_or_else(|| fields.code.to_string(), |(_, name)| name.to_string()),
+        fields.client.to_string(),
+        fields.tx.to_string(),
+        optional(fields.amount.flatten().map(|amount| amount.to_string())),
+        optional(fields.timestamp.map(|timestamp| timestamp.to_string())),
+        optional(fields.counterparty.map(|counterparty| counterparty.to_string())),
+    ])
+}
+
+/// Converts transactions to the binary format, failing on the first row that cannot be converted.
+/// Returns the number of converted transactions.
+pub fn convert<R: Read, W: Write>(mut input: Input<R>, output: W) -> Result<u64> {
+    let mut writer = BinaryWriter::new(output)?;
+    let mut count = 0;
+    while let Some(row) = input.next()? {
+        match row {
+            Row::Parsed(record, transaction) => writer
+                .write(&transaction)
+                .with_context(|| format!("failed to convert transaction on line {}", record.line()))?,
+            Row::Invalid(_, error) => return Err(error),
+        }
+        count += 1;
+    }
+    writer.finish()?;
+    Ok(count)
+}
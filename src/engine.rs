@@ -1,43 +1,141 @@
-use anyhow::{ensure, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::ops::{AddAssign, SubAssign};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::{fmt, iter};
+use LedgerAccount::*;
 use TransactionType::*;
 
 #[derive(Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Hash, Debug)]
 #[serde(transparent)]
 pub struct ClientId(u16);
 
-#[derive(Serialize, Deserialize, Eq, PartialEq, Copy, Clone, Hash, Debug)]
+impl From<u16> for ClientId {
+    fn from(client: u16) -> Self {
+        Self(client)
+    }
+}
+
+impl From<ClientId> for u16 {
+    fn from(client: ClientId) -> Self {
+        client.0
+    }
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Hash, Debug)]
 #[serde(transparent)]
 pub struct TransactionId(u32);
 
-#[derive(Serialize, Deserialize, Eq, PartialEq, PartialOrd, Copy, Clone, Debug)]
+impl From<u32> for TransactionId {
+    fn from(tx: u32) -> Self {
+        Self(tx)
+    }
+}
+
+impl From<TransactionId> for u32 {
+    fn from(tx: TransactionId) -> Self {
+        tx.0
+    }
+}
+
+/// Seconds since the unix epoch
+#[derive(Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Debug)]
+#[serde(transparent)]
+pub struct Timestamp(u64);
+
+impl From<u64> for Timestamp {
+    fn from(timestamp: u64) -> Self {
+        Self(timestamp)
+    }
+}
+
+impl From<Timestamp> for u64 {
+    fn from(timestamp: Timestamp) -> Self {
+        timestamp.0
+    }
+}
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The currency or other asset of a balance, such as `USD` or `BTC`.
+/// Transactions without an asset are in the default asset, which is empty.
+#[derive(Serialize, Eq, PartialEq, Ord, PartialOrd, Clone, Hash, Debug, Default)]
+#[serde(transparent)]
+pub struct Asset(String);
+
+impl<'de> Deserialize<'de> for Asset {
+    // Like the other optional columns, the asset may be left out at the end of a row
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self(Option::deserialize(deserializer)?.unwrap_or_default()))
+    }
+}
+
+impl From<&str> for Asset {
+    fn from(asset: &str) -> Self {
+        Self(asset.to_string())
+    }
+}
+
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, PartialOrd, Copy, Clone, Debug, Default)]
 #[serde(transparent)]
 pub struct Amount(#[serde(with = "rust_decimal::serde::str")] Decimal);
 
 impl Amount {
-    const ZERO: Self = Amount(Decimal::ZERO);
+    /// The documented precision of amounts
+    const DECIMAL_PLACES: u32 = 4;
 
     fn normalize(&self) -> Self {
         Self(self.0.normalize())
     }
-}
 
-impl AddAssign<&Self> for Amount {
-    fn add_assign(&mut self, rhs: &Self) {
-        self.0.add_assign(rhs.0)
+    /// The amount as integer number of `10^-scale` units, if it can be represented exactly.
+    pub fn to_scaled(self, scale: u32) -> Option<i128> {
+        let amount = self.0.normalize();
+        let shift = scale.checked_sub(amount.scale())?;
+        amount.mantissa().checked_mul(10i128.checked_pow(shift)?)
+    }
+
+    /// The amount of an integer number of `10^-scale` units, if it is in the range of amounts.
+    pub fn from_scaled(mut value: i128, mut scale: u32) -> Option<Self> {
+        // The mantissa of a decimal is smaller than an i128, so trailing zeros are removed first
+        while scale > 0 && value % 10 == 0 {
+            value /= 10;
+            scale -= 1;
+        }
+        Decimal::try_from_i128_with_scale(value, scale).ok().map(Self)
+    }
+
+    /// Adds to the amount, unless the result would overflow.
+    fn try_add(&mut self, rhs: &Self) -> Outcome {
+        self.0 = self.0.checked_add(rhs.0).ok_or(RejectionReason::Overflow)?;
+        Ok(())
+    }
+
+    /// Subtracts from the amount, unless the result would overflow.
+    fn try_sub(&mut self, rhs: &Self) -> Outcome {
+        self.0 = self.0.checked_sub(rhs.0).ok_or(RejectionReason::Overflow)?;
+        Ok(())
+    }
+
+    /// Adds to a statistic, which stays at the maximum rather than overflowing.
+    fn saturating_add(&mut self, rhs: &Self) {
+        self.0 = self.0.saturating_add(rhs.0);
     }
 }
 
-impl SubAssign<&Self> for Amount {
-    fn sub_assign(&mut self, rhs: &Self) {
-        self.0.sub_assign(rhs.0)
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
     }
 }
 
-#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+#[derive(Serialize, Deserialize, Eq, PartialEq, Copy, Clone, Hash, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -45,6 +143,164 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    /// Moves funds to the account of the counterparty
+    Transfer,
+    /// Operator action that reactivates a frozen or locked account
+    Unlock,
+    /// Operator action that stops funds leaving the account
+    Freeze,
+    /// Operator action that closes an empty account for good
+    Close,
+}
+
+impl TransactionType {
+    /// Whether the transaction is an action of an operator rather than of a partner.
+    pub fn is_operation(self) -> bool {
+        matches!(self, Unlock | Freeze | Close)
+    }
+}
+
+/// The lifecycle of an account.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Copy, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountState {
+    Active,
+    /// Accepts incoming funds and disputes, but no withdrawals or transfers
+    Frozen,
+    /// Locked by a chargeback, and only accepts operator actions
+    Locked,
+    /// Accepts no further transactions
+    Closed,
+}
+
+impl AccountState {
+    /// Checks whether a transaction is allowed in this state.
+    fn permits(self, transaction_type: TransactionType) -> Outcome {
+        match (self, transaction_type) {
+            (AccountState::Closed, _) => Err(RejectionReason::AccountClosed),
+            (AccountState::Locked, Unlock | Close) => Ok(()),
+            (AccountState::Locked, _) => Err(RejectionReason::AccountLocked),
+            (AccountState::Frozen, Withdrawal | Transfer) => Err(RejectionReason::AccountFrozen),
+            (AccountState::Frozen, Freeze) | (AccountState::Active, Unlock) => {
+                Err(RejectionReason::InvalidAccountState)
+            }
+            (AccountState::Frozen | AccountState::Active, _) => Ok(()),
+        }
+    }
+}
+
+/// Business rules for disputes and fees that differ between deployments.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// The transaction types that can be disputed
+    pub disputable: Vec<TransactionType>,
+    /// Whether a dispute may make the available funds negative
+    pub allow_negative_available: bool,
+    /// How often a resolved transaction can be disputed again, unlimited if absent
+    pub max_redisputes: Option<u32>,
+    /// Whether a chargeback locks the account
+    pub lock_on_chargeback: bool,
+    /// How many of the latest deposits and withdrawals of an account, rejected ones included,
+    /// can be disputed and are kept in memory, unlimited if absent.
+    /// As many of the latest transfers and operator actions are kept besides.
+    pub dispute_window: Option<usize>,
+    /// How many days after a transaction it can still be disputed, unlimited if absent.
+    /// Only applies to transactions with a timestamp. A dispute without a timestamp
+    /// is taken to arrive at the latest timestamp of the account, if there is one.
+    pub dispute_deadline_days: Option<u64>,
+    /// The fees charged for each transaction type
+    pub fees: HashMap<TransactionType, Fee>,
+    /// Groups of clients that are charged different fees
+    pub tiers: BTreeMap<String, Tier>,
+}
+
+/// A fee of a percentage of the amount of a transaction plus a flat amount.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fee {
+    pub percent: Amount,
+    pub flat: Amount,
+}
+
+impl Fee {
+    /// The fee for a transaction, rounded to four decimal places.
+    /// Transactions without an amount are only charged the flat fee.
+    fn of(&self, amount: Option<Amount>) -> Result<Amount, RejectionReason> {
+        let percentage = match amount {
+            Some(amount) => {
+                amount.0.checked_mul(self.percent.0).ok_or(RejectionReason::Overflow)? / Decimal::ONE_HUNDRED
+            }
+            None => Decimal::ZERO,
+        };
+        let fee = percentage.checked_add(self.flat.0).ok_or(RejectionReason::Overflow)?;
+        Ok(Amount(fee.round_dp(Amount::DECIMAL_PLACES)))
+    }
+}
+
+/// A group of clients whose fees differ from the default fees.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Tier {
+    pub clients: Vec<ClientId>,
+    /// Falls back to the default fee for transaction types without a fee in the tier
+    #[serde(default)]
+    pub fees: HashMap<TransactionType, Fee>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            disputable: vec![Deposit, Withdrawal],
+            allow_negative_available: true,
+            max_redisputes: None,
+            lock_on_chargeback: true,
+            dispute_window: None,
+            dispute_deadline_days: None,
+            fees: HashMap::new(),
+            tiers: BTreeMap::new(),
+        }
+    }
+}
+
+impl Policy {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.disputable
+                .iter()
+                .all(|transaction_type| matches!(transaction_type, Deposit | Withdrawal)),
+            "only deposits and withdrawals can be disputed"
+        );
+        let fees = self.tiers.values().flat_map(|tier| &tier.fees);
+        for (transaction_type, fee) in self.fees.iter().chain(fees) {
+            ensure!(!transaction_type.is_operation(), "operator actions cannot have fees");
+            ensure!(
+                !fee.percent.0.is_sign_negative() && !fee.flat.0.is_sign_negative(),
+                "fees cannot be negative"
+            );
+        }
+        let mut clients = HashSet::new();
+        for (name, tier) in &self.tiers {
+            for client in &tier.clients {
+                ensure!(
+                    clients.insert(client),
+                    "client {} of tier {} is in several tiers",
+                    client.0,
+                    name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The fee of a client for a transaction type, if any.
+    fn fee(&self, client: ClientId, transaction_type: TransactionType) -> Option<&Fee> {
+        self.tiers
+            .values()
+            .find(|tier| tier.clients.contains(&client))
+            .and_then(|tier| tier.fees.get(&transaction_type))
+            .or_else(|| self.fees.get(&transaction_type))
+    }
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
@@ -54,169 +310,1609 @@ pub struct Transaction {
     pub client: ClientId,
     pub tx: TransactionId,
     pub amount: Option<Amount>,
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+    /// Disputes, resolves and chargebacks act on the asset of the referenced transaction instead,
+    /// and are rejected if they have a different asset
+    #[serde(default)]
+    pub asset: Asset,
+    /// The client receiving a transfer
+    #[serde(default)]
+    pub counterparty: Option<ClientId>,
+}
+
+impl Transaction {
+    /// Checks that the amount is present where it is needed and absent where it has no meaning.
+    /// Disputes may have an amount to only dispute part of a transaction.
+    /// Only transfers can have a counterparty.
+    fn validate(&self) -> Outcome {
+        if self.transaction_type != Transfer && self.counterparty.is_some() {
+            return Err(RejectionReason::UnexpectedCounterparty);
+        }
+        match (self.transaction_type, self.amount) {
+            (Deposit | Withdrawal | Transfer, None) => Err(RejectionReason::MissingAmount),
+            (Resolve | Chargeback | Unlock | Freeze | Close, Some(_)) => Err(RejectionReason::UnexpectedAmount),
+            (_, Some(Amount(amount))) if amount <= Decimal::ZERO => Err(RejectionReason::AmountNotPositive),
+            (_, Some(Amount(amount))) if amount.normalize().scale() > Amount::DECIMAL_PLACES => {
+                Err(RejectionReason::AmountTooPrecise)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Why a transaction was rejected without having any effect.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub enum RejectionReason {
+    /// The transaction id has already been used, by this or any other client.
+    DuplicateTransactionId,
+    /// The referenced transaction belongs to another client.
+    ForeignTransaction { owner: ClientId },
+    /// The referenced transaction does not exist.
+    UnknownTransaction,
+    /// The available funds don't cover the withdrawal.
+    InsufficientFunds,
+    /// The account has been locked by a chargeback.
+    AccountLocked,
+    /// The account has been frozen by an operator.
+    AccountFrozen,
+    /// The account has been closed by an operator.
+    AccountClosed,
+    /// The operator action doesn't apply to the state of the account.
+    InvalidAccountState,
+    /// The account still has funds, so it cannot be closed.
+    AccountNotEmpty,
+    /// The referenced transaction is not in the right dispute state.
+    InvalidDisputeState,
+    /// The referenced transaction has been disputed too often.
+    TooManyDisputes,
+    /// The disputed amount exceeds the part of the transaction that isn't disputed yet.
+    DisputedAmountTooLarge,
+    /// The referenced transaction cannot be disputed.
+    NotDisputable,
+    /// The referenced transaction has left the dispute window.
+    Expired,
+    /// A partner sent an operator action, which only operators may take.
+    OperatorOnly,
+    /// The asset differs from the asset of the referenced transaction.
+    AssetMismatch,
+    /// The dispute arrived after the deadline for disputing the referenced transaction.
+    DeadlineExceeded,
+    /// The transaction is older than the previous transaction of the client.
+    TimestampNotMonotonic,
+    /// A deposit, withdrawal or transfer has no amount.
+    MissingAmount,
+    /// A resolve, chargeback or operator action has an amount.
+    UnexpectedAmount,
+    /// The amount is zero or negative.
+    AmountNotPositive,
+    /// The amount has more than four decimal places.
+    AmountTooPrecise,
+    /// A balance would exceed the range of amounts.
+    Overflow,
+    /// A transfer has no counterparty, or is to the same client.
+    InvalidCounterparty,
+    /// A transaction other than a transfer has a counterparty.
+    UnexpectedCounterparty,
+    /// The account receiving a transfer has been locked or closed.
+    CounterpartyUnavailable,
+}
+
+impl fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DuplicateTransactionId => write!(f, "duplicate transaction id"),
+            Self::ForeignTransaction { owner } => write!(f, "transaction belongs to client {}", owner.0),
+            Self::UnknownTransaction => write!(f, "unknown transaction"),
+            Self::InsufficientFunds => write!(f, "insufficient funds"),
+            Self::AccountLocked => write!(f, "account locked"),
+            Self::AccountFrozen => write!(f, "account frozen"),
+            Self::AccountClosed => write!(f, "account closed"),
+            Self::InvalidAccountState => write!(f, "invalid account state"),
+            Self::AccountNotEmpty => write!(f, "account not empty"),
+            Self::InvalidDisputeState => write!(f, "invalid dispute state"),
+            Self::TooManyDisputes => write!(f, "too many disputes"),
+            Self::DisputedAmountTooLarge => write!(f, "disputed amount too large"),
+            Self::NotDisputable => write!(f, "transaction not disputable"),
+            Self::Expired => write!(f, "transaction expired"),
+            Self::OperatorOnly => write!(f, "operator action not allowed"),
+            Self::AssetMismatch => write!(f, "asset mismatch"),
+            Self::DeadlineExceeded => write!(f, "dispute deadline exceeded"),
+            Self::TimestampNotMonotonic => write!(f, "timestamp before previous transaction"),
+            Self::MissingAmount => write!(f, "missing amount"),
+            Self::UnexpectedAmount => write!(f, "unexpected amount"),
+            Self::AmountNotPositive => write!(f, "amount not positive"),
+            Self::Overflow => write!(f, "balance overflow"),
+            Self::AmountTooPrecise => write!(f, "amount has more than {} decimal places", Amount::DECIMAL_PLACES),
+            Self::InvalidCounterparty => write!(f, "invalid counterparty"),
+            Self::UnexpectedCounterparty => write!(f, "unexpected counterparty"),
+            Self::CounterpartyUnavailable => write!(f, "counterparty account unavailable"),
+        }
+    }
+}
+
+/// The outcome of a transaction that could be handled.
+/// Fatal errors are reported separately.
+pub type Outcome = Result<(), RejectionReason>;
+
+/// Records every handled transaction together with its planned transition, before it is applied.
+pub trait Journal: Send {
+    fn record(&mut self, transaction: &Transaction, planned: &Planned) -> Result<()>;
+}
+
+/// Routes transactions to the accounts of their clients.
+#[derive(Serialize, Deserialize)]
+pub struct Engine {
+    registry: Registry,
+    accounts: Accounts,
+    #[serde(skip)]
+    journal: Option<Box<dyn Journal>>,
+}
+
+impl Engine {
+    pub fn new(policy: Policy) -> Engine {
+        Self::from_parts(Registry::new(policy.dispute_window), Accounts::new(policy))
+    }
+
+    pub fn from_parts(registry: Registry, accounts: Accounts) -> Engine {
+        Self {
+            registry,
+            accounts,
+            journal: None,
+        }
+    }
+
+    pub fn set_journal(&mut self, journal: Box<dyn Journal>) {
+        self.journal = Some(journal);
+    }
+
+    pub fn journaled(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    /// Verifies the consistency of the accounts now and after every transaction from now on.
+    pub fn enable_checks(&mut self) -> Result<()> {
+        self.accounts.enable_checks()
+    }
+
+    pub fn checked(&self) -> bool {
+        self.accounts.checker.is_some()
+    }
+
+    /// Replaces the policy, such as after restoring the engine from a snapshot.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.registry.window = policy.dispute_window;
+        self.accounts.policy = policy;
+    }
+
+    /// Splits the engine, so that the accounts can be handled independently of the registry.
+    /// The journal is dropped, as it relies on the order in which the engine handles the transactions.
+    pub fn into_parts(self) -> (Registry, Accounts) {
+        (self.registry, self.accounts)
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.iter()
+    }
+
+    pub fn account(&self, client: ClientId) -> Option<&Account> {
+        self.accounts.get(client)
+    }
+
+    pub fn fees(&self) -> Vec<FeeInfo> {
+        self.accounts.fees()
+    }
+
+    /// The executed transaction with the id, unless it has been evicted after leaving the dispute window.
+    pub fn transaction(&self, tx: TransactionId) -> Option<TransactionInfo> {
+        let client = self.registry.owners.get(&tx)?;
+        self.accounts.get(*client)?.transaction(tx)
+    }
+
+    pub fn handle(&mut self, transaction: Transaction) -> Result<Outcome> {
+        let registered = self.registry.register(&transaction);
+        let journal = self
+            .journal
+            .as_mut()
+            .map(|journal| journal.as_mut() as &mut dyn Journal);
+        self.accounts.handle(transaction, registered, journal)
+    }
+
+    /// Handles a transaction sent by a partner, who cannot take operator actions
+    /// such as unlocking their own account after a chargeback.
+    pub fn handle_partner(&mut self, transaction: Transaction) -> Result<Outcome> {
+        if transaction.transaction_type.is_operation() {
+            return Ok(Err(RejectionReason::OperatorOnly));
+        }
+        self.handle(transaction)
+    }
+
+    /// Applies a journaled transaction again, without deciding about it anew.
+    pub fn replay(&mut self, transaction: Transaction, planned: Planned) -> Result<()> {
+        let registered = self.registry.register(&transaction);
+        match &registered {
+            // The account may still keep an expired transaction for an open dispute
+            Err(RejectionReason::Expired) => {}
+            Err(reason) => ensure!(
+                planned == Err(reason.clone()),
+                "transaction {:?} was not rejected as {}",
+                transaction,
+                reason
+            ),
+            Ok(()) => {}
+        }
+        self.accounts.replay(transaction, registered, planned);
+        Ok(())
+    }
+}
+
+/// Transaction ids are unique across all clients, so the registry claims the id of every new
+/// transaction, and keeps track of which client the transactions in the dispute windows belong to.
+///
+/// It mirrors the dispute windows of the accounts: every deposit or withdrawal that it registers
+/// takes a slot in the window of its client, and so does every transfer or operator action in a
+/// window of its own, whether or not the account accepts the transaction in the end.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Registry {
+    // The dispute window is configuration rather than state
+    #[serde(skip)]
+    window: Option<usize>,
+    claimed: IdSet,
+    owners: HashMap<TransactionId, ClientId>,
+    windows: HashMap<ClientId, Windows>,
+}
+
+/// The latest transactions of a client, oldest first.
+#[derive(Serialize, Deserialize, Default)]
+struct Windows {
+    /// The deposits and withdrawals, which can be disputed
+    disputable: VecDeque<TransactionId>,
+    /// The transfers and operator actions
+    others: VecDeque<TransactionId>,
+}
+
+impl Registry {
+    pub fn new(window: Option<usize>) -> Registry {
+        Self {
+            window,
+            ..Self::default()
+        }
+    }
+
+    /// Validates the transaction, claims the id of new transactions
+    /// and checks that referenced transactions belong to the same client.
+    ///
+    /// A transaction that left the dispute window is reported as expired, which
+    /// the account overrides if it still keeps the transaction for an open dispute.
+    pub fn register(&mut self, transaction: &Transaction) -> Outcome {
+        transaction.validate()?;
+        let tx = transaction.tx;
+        let client = transaction.client;
+        match transaction.transaction_type {
+            Dispute | Resolve | Chargeback => match self.owners.get(&tx) {
+                Some(&owner) if owner != client => Err(RejectionReason::ForeignTransaction { owner }),
+                Some(_) => Ok(()),
+                None if self.claimed.contains(tx) => Err(RejectionReason::Expired),
+                None => Err(RejectionReason::UnknownTransaction),
+            },
+            transaction_type => {
+                if !self.claimed.insert(tx) {
+                    return Err(RejectionReason::DuplicateTransactionId);
+                }
+                self.owners.insert(tx, client);
+                let windows = self.windows.entry(client).or_default();
+                let window = match transaction_type {
+                    Deposit | Withdrawal => &mut windows.disputable,
+                    _ => &mut windows.others,
+                };
+                window.push_back(tx);
+                if let Some(limit) = self.window {
+                    while window.len() > limit {
+                        if let Some(evicted) = window.pop_front() {
+                            self.owners.remove(&evicted);
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A set of transaction ids, in pages of bits that are allocated as they are needed.
+/// Even with every id taken, it needs no more than 512 MiB.
+#[derive(Default)]
+struct IdSet {
+    pages: BTreeMap<u16, Box<[u64; PAGE_WORDS]>>,
+}
+
+/// The number of words of a page, which holds the ids that share the upper 16 bits
+const PAGE_WORDS: usize = (1 << 16) / 64;
+
+impl IdSet {
+    fn locate(tx: TransactionId) -> (u16, usize, u64) {
+        let page = (tx.0 >> 16) as u16;
+        let bit = (tx.0 & 0xffff) as usize;
+        (page, bit / 64, 1 << (bit % 64))
+    }
+
+    fn contains(&self, tx: TransactionId) -> bool {
+        let (page, word, mask) = Self::locate(tx);
+        self.pages.get(&page).is_some_and(|words| words[word] & mask != 0)
+    }
+
+    /// Adds the id, returning whether it was new.
+    fn insert(&mut self, tx: TransactionId) -> bool {
+        let (page, word, mask) = Self::locate(tx);
+        let words = self.pages.entry(page).or_insert_with(|| Box::new([0; PAGE_WORDS]));
+        let new = words[word] & mask == 0;
+        words[word] |= mask;
+        new
+    }
+
+    /// The ids in increasing order.
+    fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.pages.iter().flat_map(|(&page, words)| {
+            words.iter().enumerate().flat_map(move |(word, &bits)| {
+                (0..64)
+                    .filter(move |bit| bits & (1 << bit) != 0)
+                    .map(move |bit| (u32::from(page) << 16) | (word * 64 + bit) as u32)
+            })
+        })
+    }
+}
+
+/// Ids are mostly consecutive, so the set is serialized as inclusive ranges of ids.
+impl Serialize for IdSet {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        for tx in self.iter() {
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == tx => *end = tx,
+                _ => ranges.push((tx, tx)),
+            }
+        }
+        ranges.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for IdSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut set = IdSet::default();
+        for (start, end) in Vec::<(u32, u32)>::deserialize(deserializer)? {
+            for tx in start..=end {
+                set.insert(TransactionId(tx));
+            }
+        }
+        Ok(set)
+    }
+}
+
+/// The accounts of a set of clients.
+///
+/// Apart from transfers, transactions only ever concern one client, so the
+/// accounts can be partitioned and handled independently of each other.
+/// A transfer between partitions is planned on the side of the counterparty
+/// with `plan_credit`, and is then handled by the sender with `handle_debit`.
+#[derive(Serialize, Deserialize)]
+pub struct Accounts {
+    // The policy is configuration rather than state
+    #[serde(skip)]
+    policy: Policy,
+    // We sort the accounts by client id for more predictable output
+    accounts: BTreeMap<ClientId, Account>,
+    /// The fees collected by the house, per asset
+    house: BTreeMap<Asset, Amount>,
+    /// Whether the accounts are checked after every transaction, which is a debugging aid
+    #[serde(skip)]
+    checker: Option<Checker>,
+}
+
+impl Accounts {
+    pub fn new(policy: Policy) -> Accounts {
+        Self {
+            policy,
+            accounts: BTreeMap::new(),
+            house: BTreeMap::new(),
+            checker: None,
+        }
+    }
+
+    fn enable_checks(&mut self) -> Result<()> {
+        let mut checker = Checker::default();
+        for account in self.accounts.values() {
+            if let Err(violation) = account.verify() {
+                bail!("{}\naccount: {}", violation, serde_json::to_string(account)?);
+            }
+            for (asset, balance) in &account.balances {
+                add(&mut checker.totals, asset, &balance.total)?;
+            }
+            for (asset, paid) in &account.evicted {
+                add(&mut checker.paid, asset, paid)?;
+            }
+            for posting in &account.postings {
+                add(&mut checker.paid, &posting.asset, &posting.paid())?;
+            }
+        }
+        let assets: BTreeSet<_> = checker.totals.keys().chain(checker.paid.keys()).cloned().collect();
+        for asset in &assets {
+            checker.verify(asset).map_err(|violation| anyhow!(violation))?;
+        }
+        self.checker = Some(checker);
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.values()
+    }
+
+    pub fn get(&self, client: ClientId) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    /// The fees collected from each client, followed by the fees collected by the house.
+    pub fn fees(&self) -> Vec<FeeInfo> {
+        let house = self.house.iter().map(|(asset, fees)| FeeInfo {
+            client: None,
+            asset: asset.clone(),
+            fees: fees.normalize(),
+        });
+        self.accounts.values().flat_map(Account::fees).chain(house).collect()
+    }
+
+    /// Handles a transaction after it has been checked by the `Registry`.
+    /// The account of the client is opened even if the transaction was rejected.
+    pub fn handle(
+        &mut self,
+        transaction: Transaction,
+        registered: Outcome,
+        journal: Option<&mut dyn Journal>,
+    ) -> Result<Outcome> {
+        let planned = self.plan(&transaction, registered)?;
+        let planned = match planned {
+            Ok(transition) if transaction.transaction_type == Transfer => self.credit(&transaction, transition)?,
+            planned => planned,
+        };
+        self.conclude(transaction, planned, journal)
+    }
+
+    /// Handles the sending side of a transfer to a client of another partition,
+    /// given whether the counterparty can receive it according to `plan_credit`.
+    pub fn handle_debit(&mut self, transaction: Transaction, registered: Outcome, credit: Outcome) -> Result<Outcome> {
+        let planned = self.plan(&transaction, registered)?;
+        let planned = planned.and_then(|transition| credit.map(|()| transition));
+        self.conclude(transaction, planned, None)
+    }
+
+    /// Plans the receiving side of a transfer from a client of another partition.
+    /// Unlike the sender, the counterparty only gets an account once the transfer is applied.
+    pub fn plan_credit(&self, transaction: &Transaction) -> Result<Planned> {
+        let counterparty = transaction.counterparty.context("transfer has no counterparty")?;
+        Ok(match self.accounts.get(&counterparty) {
+            Some(account) => account.credit(transaction),
+            None => Account::new(counterparty).credit(transaction),
+        })
+    }
+
+    /// Applies the receiving side of a transfer planned by `plan_credit`, once the sender accepted it.
+    pub fn apply_credit(&mut self, transaction: &Transaction, credit: Transition) -> Result<()> {
+        let counterparty = transaction.counterparty.context("transfer has no counterparty")?;
+        // The account is opened beforehand, so that the checks see how it changed
+        self.accounts
+            .entry(counterparty)
+            .or_insert_with(|| Account::new(counterparty));
+        let expected = vec![(transaction.asset.clone(), transaction.amount.unwrap_or_default())];
+        self.checked(transaction, &[counterparty], expected, false, |accounts| {
+            if let Some(account) = accounts.accounts.get_mut(&counterparty) {
+                account.update(credit);
+            }
+        })
+    }
+
+    /// Plans the transaction on the account of the client, which is opened even if the transaction is rejected.
+    fn plan(&mut self, transaction: &Transaction, registered: Outcome) -> Result<Planned> {
+        let client = transaction.client;
+        let account = self.accounts.entry(client).or_insert_with(|| Account::new(client));
+        account.claim(transaction, &registered, &self.policy);
+        match registered {
+            Ok(()) => account.plan(transaction, &self.policy),
+            Err(RejectionReason::Expired) if account.keeps(transaction.tx) => account.plan(transaction, &self.policy),
+            Err(reason) => Ok(Err(reason)),
+        }
+    }
+
+    /// Records the planned transaction in the journal and settles it.
+    fn conclude(
+        &mut self,
+        transaction: Transaction,
+        planned: Planned,
+        journal: Option<&mut dyn Journal>,
+    ) -> Result<Outcome> {
+        if let Some(journal) = journal {
+            journal.record(&transaction, &planned)?;
+        }
+        match planned {
+            planned if self.checker.is_some() => self.settle_checked(transaction, planned),
+            planned => {
+                let outcome = planned.as_ref().map(|_| ()).map_err(Clone::clone);
+                self.settle(transaction, planned);
+                Ok(outcome)
+            }
+        }
+    }
+
+    /// Applies an accepted transaction, and keeps a withdrawal that failed for insufficient funds.
+    fn settle(&mut self, transaction: Transaction, planned: Planned) {
+        match planned {
+            Ok(transition) => self.apply(transaction, transition),
+            Err(RejectionReason::InsufficientFunds) if transaction.transaction_type == Withdrawal => {
+                let client = transaction.client;
+                let account = self.accounts.entry(client).or_insert_with(|| Account::new(client));
+                account.fail(transaction);
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Settles the transaction and verifies the accounts of its clients, failing with a dump
+    /// of the first inconsistent account:
+    /// - The available and held funds add up to the total
+    /// - The held funds are those of the open disputes
+    /// - The balances of locked accounts and of rejected transactions don't change
+    /// - The totals of all clients are the deposits minus the withdrawals and chargebacks,
+    ///   as well as the credits of disputed withdrawals and the fees
+    fn settle_checked(&mut self, transaction: Transaction, planned: Planned) -> Result<Outcome> {
+        let outcome = planned.as_ref().map(|_| ()).map_err(Clone::clone);
+        let expected = match &planned {
+            Ok(transition) => self.expected(&transaction, transition)?,
+            Err(_) => Vec::new(),
+        };
+        let clients: Vec<_> = iter::once(transaction.client).chain(transaction.counterparty).collect();
+        self.checked(&transaction, &clients, expected, outcome.is_err(), |accounts| {
+            accounts.settle(transaction.clone(), planned)
+        })?;
+        Ok(outcome)
+    }
+
+    /// Changes the accounts of the clients and verifies them, expecting the totals to change as given.
+    fn checked(
+        &mut self,
+        transaction: &Transaction,
+        clients: &[ClientId],
+        expected: Vec<(Asset, Amount)>,
+        rejected: bool,
+        change: impl FnOnce(&mut Self),
+    ) -> Result<()> {
+        let before: Vec<_> = clients
+            .iter()
+            .filter_map(|client| self.accounts.get(client))
+            .map(|account| (account.client, account.state, account.balances.clone()))
+            .collect();
+        change(self);
+
+        let Some(checker) = &mut self.checker else {
+            return Ok(());
+        };
+        let dump = |client: &ClientId| serde_json::to_string(&self.accounts[client]);
+        let mut assets = BTreeSet::new();
+        for (asset, amount) in expected {
+            add(&mut checker.paid, &asset, &amount)?;
+            assets.insert(asset);
+        }
+        for (client, state, balances) in &before {
+            let account = &self.accounts[client];
+            let mut violation = account.verify();
+            if state == &AccountState::Locked && &account.balances != balances {
+                violation = Err("locked account changed".to_string());
+            }
+            if rejected && &account.balances != balances {
+                violation = Err("rejected transaction changed the balances".to_string());
+            }
+            if let Err(violation) = violation {
+                bail!(
+                    "{} after transaction {:?}\naccount: {}",
+                    violation,
+                    transaction,
+                    dump(client)?
+                );
+            }
+            for (asset, balance) in balances {
+                add(&mut checker.totals, asset, &Amount(-balance.total.0))?;
+                assets.insert(asset.clone());
+            }
+            for (asset, balance) in &account.balances {
+                add(&mut checker.totals, asset, &balance.total)?;
+                assets.insert(asset.clone());
+            }
+        }
+        for asset in &assets {
+            if let Err(violation) = checker.verify(asset) {
+                let accounts = before
+                    .iter()
+                    .map(|(client, _, _)| dump(client).map(|account| format!("\naccount: {}", account)))
+                    .collect::<Result<String, _>>()?;
+                bail!("{} after transaction {:?}{}", violation, transaction, accounts);
+            }
+        }
+        Ok(())
+    }
+
+    /// How an accepted transaction changes the totals of the clients in this partition, by the type and
+    /// amounts of the transaction and the transaction it refers to, rather than by its postings.
+    fn expected(&self, transaction: &Transaction, transition: &Transition) -> Result<Vec<(Asset, Amount)>> {
+        let amount = transaction.amount.unwrap_or_default().0;
+        let referenced = || {
+            self.accounts
+                .get(&transaction.client)
+                .and_then(|account| account.transactions.get(&transaction.tx))
+                .map(|executed| (executed.transaction.transaction_type, executed.state.disputed.0))
+                .context("disputed transaction not found")
+        };
+        let change = match transaction.transaction_type {
+            Deposit => amount,
+            Withdrawal => -amount,
+            // The counterparty might be handled in another partition
+            Transfer if transition.counterparty.is_some() => Decimal::ZERO,
+            Transfer => -amount,
+            Dispute => match referenced()? {
+                (Withdrawal, disputed) => transition.state.disputed.0 - disputed,
+                _ => Decimal::ZERO,
+            },
+            Resolve => match referenced()? {
+                (Withdrawal, disputed) => -disputed,
+                _ => Decimal::ZERO,
+            },
+            Chargeback => match referenced()? {
+                (Withdrawal, _) => Decimal::ZERO,
+                (_, disputed) => -disputed,
+            },
+            Unlock | Freeze | Close => Decimal::ZERO,
+        };
+        let fee = transition.fee.unwrap_or_default().0;
+        Ok(vec![(transition.asset.clone(), Amount(change - fee))])
+    }
+
+    /// Plans the receiving side of a transfer, so that both sides are applied together or not at all.
+    fn credit(&mut self, transaction: &Transaction, transition: Transition) -> Result<Planned> {
+        let counterparty = transaction.counterparty.context("transfer has no counterparty")?;
+        let account = self
+            .accounts
+            .entry(counterparty)
+            .or_insert_with(|| Account::new(counterparty));
+        Ok(account.credit(transaction).map(|credit| Transition {
+            counterparty: Some(Box::new(credit)),
+            ..transition
+        }))
+    }
+
+    fn apply(&mut self, transaction: Transaction, mut transition: Transition) {
+        if let (Some(credit), Some(counterparty)) = (transition.counterparty.take(), transaction.counterparty) {
+            let account = self
+                .accounts
+                .entry(counterparty)
+                .or_insert_with(|| Account::new(counterparty));
+            account.update(*credit);
+        }
+        if let Some(fee) = &transition.fee {
+            self.house
+                .entry(transition.asset.clone())
+                .or_default()
+                .saturating_add(fee);
+        }
+        let client = transaction.client;
+        let account = self.accounts.entry(client).or_insert_with(|| Account::new(client));
+        account.apply(transaction, transition);
+    }
+
+    fn replay(&mut self, transaction: Transaction, registered: Outcome, planned: Planned) {
+        let client = transaction.client;
+        let account = self.accounts.entry(client).or_insert_with(|| Account::new(client));
+        account.claim(&transaction, &registered, &self.policy);
+        self.settle(transaction, planned);
+    }
+
+    /// Distributes the accounts onto `count` partitions, by the partition of their client.
+    pub fn partition(self, count: usize, partition: impl Fn(ClientId) -> usize) -> Vec<Accounts> {
+        let mut partitions: Vec<Accounts> = (0..count).map(|_| Accounts::new(self.policy.clone())).collect();
+        for (client, account) in self.accounts {
+            partitions[partition(client)].accounts.insert(client, account);
+        }
+        // Each partition collects fees separately, which add up when merging
+        if let Some(first) = partitions.first_mut() {
+            first.house = self.house;
+        }
+        // Likewise, each partition checks the sums of its changes, and the first one the sums so far
+        if let Some(checker) = self.checker {
+            for partition in &mut partitions {
+                partition.checker = Some(Checker::default());
+            }
+            if let Some(first) = partitions.first_mut() {
+                first.checker = Some(checker);
+            }
+        }
+        partitions
+    }
+
+    /// Combines two partitions of accounts again.
+    pub fn merge(&mut self, partition: Accounts) {
+        self.accounts.extend(partition.accounts);
+        for (asset, fees) in partition.house {
+            self.house.entry(asset).or_default().saturating_add(&fees);
+        }
+        if let (Some(checker), Some(partition)) = (&mut self.checker, partition.checker) {
+            for (asset, total) in partition.totals {
+                let sum = checker.totals.entry(asset).or_default();
+                *sum = sum.saturating_add(total);
+            }
+            for (asset, paid) in partition.paid {
+                let sum = checker.paid.entry(asset).or_default();
+                *sum = sum.saturating_add(paid);
+            }
+        }
+    }
+}
+
+/// Sums up the funds of all clients in two ways, which have to match.
+/// The sums are kept in units of the smallest amount, as they can exceed the range of amounts.
+#[derive(Clone, Default)]
+struct Checker {
+    /// The totals of the balances of all clients, per asset
+    totals: BTreeMap<Asset, i128>,
+    /// What the house paid to the clients minus what it received from them, per asset,
+    /// according to the ledger when the checks start and to the transactions from then on
+    paid: BTreeMap<Asset, i128>,
 }
 
+impl Checker {
+    fn verify(&self, asset: &Asset) -> Result<(), String> {
+        let total = self.totals.get(asset).copied().unwrap_or_default();
+        let paid = self.paid.get(asset).copied().unwrap_or_default();
+        if total != paid {
+            let describe = |sum: i128| {
+                Amount::from_scaled(sum, Amount::DECIMAL_PLACES)
+                    .map_or_else(|| format!("{}e-{}", sum, Amount::DECIMAL_PLACES), |sum| sum.to_string())
+            };
+            return Err(format!(
+                "total funds of all clients {} differ from the {} paid to them in asset '{}'",
+                describe(total),
+                describe(paid),
+                asset
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn add(sums: &mut BTreeMap<Asset, i128>, asset: &Asset, amount: &Amount) -> Result<()> {
+    let amount = amount
+        .to_scaled(Amount::DECIMAL_PLACES)
+        .with_context(|| format!("amount {} is too precise to be checked", amount))?;
+    let sum = sums.entry(asset.clone()).or_default();
+    *sum = sum
+        .checked_add(amount)
+        .context("overflow while checking the accounts")?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Account {
     client: ClientId,
+    balances: BTreeMap<Asset, Balance>,
+    /// The fees collected from the client, per asset
+    fees: BTreeMap<Asset, Amount>,
+    state: AccountState,
+    /// The timestamp of the latest transaction
+    latest: Option<Timestamp>,
+    /// The postings of the ledger accounts of the client, oldest first,
+    /// back to the oldest deposit or withdrawal that is kept
+    postings: VecDeque<Posting>,
+    /// What the house paid to the client in the postings that have been evicted, per asset
+    evicted: BTreeMap<Asset, Amount>,
+    transactions: HashMap<TransactionId, AccountTransaction>,
+    /// The deposits and withdrawals in the dispute window, oldest first, including rejected ones
+    window: VecDeque<TransactionId>,
+    /// The latest transfers and operator actions, oldest first, which take no slots in the dispute window
+    others: VecDeque<TransactionId>,
+}
+
+/// The funds of an account in one asset, which are the sums of the postings of the account.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug, Default)]
+struct Balance {
     available: Amount,
     held: Amount,
+    /// Changed only by postings from or to other ledger accounts than those of the client
     total: Amount,
-    locked: bool,
-    transactions: HashMap<TransactionId, AccountTransaction>,
+}
+
+impl Balance {
+    /// Books a posting, of which at least one side is a ledger account of the client.
+    /// The funds of the client are liabilities of the house, so credits add to them.
+    fn post(&mut self, client: ClientId, posting: &Posting) -> Outcome {
+        let amount = &posting.amount;
+        if let Some(credited) = self.funds(client, posting.credit) {
+            credited.try_add(amount)?;
+        }
+        if let Some(debited) = self.funds(client, posting.debit) {
+            debited.try_sub(amount)?;
+        }
+        match (
+            posting.credit.client() == Some(client),
+            posting.debit.client() == Some(client),
+        ) {
+            (true, false) => self.total.try_add(amount),
+            (false, true) => self.total.try_sub(amount),
+            _ => Ok(()),
+        }
+    }
+
+    fn funds(&mut self, client: ClientId, account: LedgerAccount) -> Option<&mut Amount> {
+        match account {
+            Available(owner) if owner == client => Some(&mut self.available),
+            Held(owner) if owner == client => Some(&mut self.held),
+            _ => None,
+        }
+    }
+}
+
+/// The accounts of the double-entry ledger, between which postings move funds.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Copy, Clone, Debug)]
+#[serde(into = "String", try_from = "String")]
+pub enum LedgerAccount {
+    /// The funds a client can withdraw or transfer
+    Available(ClientId),
+    /// The funds of a client that are held by disputes
+    Held(ClientId),
+    /// The funds the house keeps for the clients, which deposits add to
+    HouseCash,
+    /// The provisional credits of disputed withdrawals, which the house loses on chargeback
+    ChargebackLosses,
+    /// The fees collected by the house
+    Fees,
+}
+
+impl LedgerAccount {
+    fn client(self) -> Option<ClientId> {
+        match self {
+            Available(client) | Held(client) => Some(client),
+            HouseCash | ChargebackLosses | Fees => None,
+        }
+    }
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Available(ClientId(client)) => write!(f, "available:{}", client),
+            Held(ClientId(client)) => write!(f, "held:{}", client),
+            HouseCash => write!(f, "house:cash"),
+            ChargebackLosses => write!(f, "house:chargeback_losses"),
+            Fees => write!(f, "house:fees"),
+        }
+    }
+}
+
+impl From<LedgerAccount> for String {
+    fn from(account: LedgerAccount) -> Self {
+        account.to_string()
+    }
+}
+
+impl TryFrom<String> for LedgerAccount {
+    type Error = anyhow::Error;
+
+    fn try_from(account: String) -> Result<Self> {
+        Ok(match account.split_once(':') {
+            Some(("available", client)) => Available(ClientId(client.parse()?)),
+            Some(("held", client)) => Held(ClientId(client.parse()?)),
+            Some(("house", "cash")) => HouseCash,
+            Some(("house", "chargeback_losses")) => ChargebackLosses,
+            Some(("house", "fees")) => Fees,
+            _ => return Err(anyhow!("unknown ledger account {}", account)),
+        })
+    }
+}
+
+/// Moves an amount from the debited to the credited ledger account, so that the ledger stays balanced.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct Posting {
+    pub tx: TransactionId,
+    pub asset: Asset,
+    pub debit: LedgerAccount,
+    pub credit: LedgerAccount,
+    pub amount: Amount,
+}
+
+impl Posting {
+    /// The amount the posting moves from the house to a client, which is negative the other way round.
+    fn paid(&self) -> Amount {
+        match (self.debit.client(), self.credit.client()) {
+            (None, Some(_)) => self.amount,
+            (Some(_), None) => Amount(-self.amount.0),
+            _ => Amount::default(),
+        }
+    }
 }
 
 #[derive(Serialize, Eq, PartialEq, Clone, Debug)]
 pub struct AccountInfo {
     pub client: ClientId,
+    pub asset: Asset,
     pub available: Amount,
     pub held: Amount,
     pub total: Amount,
+    /// Whether the account is locked by a chargeback, kept alongside the state for compatibility
     pub locked: bool,
+    pub state: AccountState,
+}
+
+/// An executed deposit, withdrawal or transfer with the state of its disputes.
+#[derive(Serialize, Eq, PartialEq, Clone, Debug)]
+pub struct TransactionInfo {
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub asset: Asset,
+    pub amount: Option<Amount>,
+    pub counterparty: Option<ClientId>,
+    pub timestamp: Option<Timestamp>,
+    pub dispute: DisputeState,
+    pub disputes: u32,
+    /// The part of the amount that is currently disputed
+    pub disputed: Amount,
+    /// The part of the amount that has been charged back
+    pub charged_back: Amount,
+}
+
+/// The fees collected from a client in an asset, or by the house if there is no client.
+#[derive(Serialize, Eq, PartialEq, Clone, Debug)]
+pub struct FeeInfo {
+    pub client: Option<ClientId>,
+    pub asset: Asset,
+    pub fees: Amount,
 }
 
+#[derive(Serialize, Deserialize)]
 struct AccountTransaction {
     transaction: Transaction,
     state: TransactionState,
+    /// Disputed transactions are only evicted once the dispute is settled
+    outside_window: bool,
+    /// A withdrawal rejected for insufficient funds, which is kept so that disputes of it are not disputable
+    failed: bool,
 }
 
-enum TransactionState {
-    Failed,
-    Executed { dispute: DisputeState },
+impl AccountTransaction {
+    fn info(&self) -> TransactionInfo {
+        let transaction = &self.transaction;
+        TransactionInfo {
+            transaction_type: transaction.transaction_type,
+            client: transaction.client,
+            tx: transaction.tx,
+            asset: transaction.asset.clone(),
+            amount: transaction.amount.as_ref().map(Amount::normalize),
+            counterparty: transaction.counterparty,
+            timestamp: transaction.timestamp,
+            dispute: self.state.dispute,
+            disputes: self.state.disputes,
+            disputed: self.state.disputed.normalize(),
+            charged_back: self.state.charged_back.normalize(),
+        }
+    }
 }
+
+/// The state of an executed deposit or withdrawal.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Copy, Clone, Debug)]
+pub struct TransactionState {
+    dispute: DisputeState,
+    /// How often the transaction has been disputed, not counting partial disputes of an open dispute
+    disputes: u32,
+    /// The part of the amount that is currently disputed
+    disputed: Amount,
+    /// The part of the amount that has been charged back, which cannot be disputed again
+    charged_back: Amount,
+}
+
 impl TransactionState {
     fn executed() -> Self {
-        Self::Executed {
+        Self {
             dispute: DisputeState::Undisputed,
+            disputes: 0,
+            disputed: Amount::default(),
+            charged_back: Amount::default(),
         }
     }
 }
 
-enum DisputeState {
+#[derive(Serialize, Deserialize, Eq, PartialEq, Copy, Clone, Debug)]
+pub enum DisputeState {
     Undisputed,
     Disputed,
     Resolved,
     Chargeback,
 }
 
+/// The state of an account after an accepted transaction.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct Transition {
+    /// The asset of the transaction, or of the transaction it refers to
+    asset: Asset,
+    balance: Balance,
+    account_state: AccountState,
+    latest: Option<Timestamp>,
+    /// The state of the transaction, or of the transaction it refers to
+    state: TransactionState,
+    /// The transition of the account receiving a transfer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    counterparty: Option<Box<Transition>>,
+    /// The fee charged to the account, which is credited to the house
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fee: Option<Amount>,
+    /// The postings that led to the balance
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    postings: Vec<Posting>,
+}
+
+/// The transition of an accepted transaction, or the reason it was rejected.
+pub type Planned = Result<Transition, RejectionReason>;
+
 impl Account {
     pub fn new(client: ClientId) -> Account {
         Self {
             client,
-            available: Amount::ZERO,
-            held: Amount::ZERO,
-            total: Amount::ZERO,
-            locked: false,
+            balances: BTreeMap::new(),
+            fees: BTreeMap::new(),
+            state: AccountState::Active,
+            latest: None,
+            postings: VecDeque::new(),
+            evicted: BTreeMap::new(),
             transactions: HashMap::new(),
+            window: VecDeque::new(),
+            others: VecDeque::new(),
         }
     }
 
-    pub fn info(&self) -> AccountInfo {
+    /// The balance of the account in an asset it holds.
+    pub fn balance(&self, asset: &Asset) -> Option<AccountInfo> {
+        self.balances.contains_key(asset).then(|| self.info(asset))
+    }
+
+    /// The balance of the account in an asset, which is empty if the account never held the asset.
+    fn info(&self, asset: &Asset) -> AccountInfo {
+        let balance = self.balances.get(asset).cloned().unwrap_or_default();
         AccountInfo {
             client: self.client,
-            available: self.available.normalize(),
-            held: self.held.normalize(),
-            total: self.total.normalize(),
-            locked: self.locked,
+            asset: asset.clone(),
+            available: balance.available.normalize(),
+            held: balance.held.normalize(),
+            total: balance.total.normalize(),
+            locked: self.state == AccountState::Locked,
+            state: self.state,
         }
     }
 
-    pub fn handle(&mut self, transaction: Transaction) -> Result<()> {
+    /// The balances of the account in all assets it holds, sorted by asset.
+    /// An account without any balance still reports an empty balance in the default asset.
+    pub fn infos(&self) -> Vec<AccountInfo> {
+        if self.balances.is_empty() {
+            return vec![self.info(&Asset::default())];
+        }
+        self.balances.keys().map(|asset| self.info(asset)).collect()
+    }
+
+    pub fn fees(&self) -> impl Iterator<Item = FeeInfo> + '_ {
+        self.fees.iter().map(|(asset, fees)| FeeInfo {
+            client: Some(self.client),
+            asset: asset.clone(),
+            fees: fees.normalize(),
+        })
+    }
+
+    /// The postings of the ledger accounts of the client, oldest first.
+    /// A transfer is posted in the history of both clients.
+    /// With a dispute window, the postings before the oldest deposit or withdrawal that is kept have been evicted.
+    pub fn postings(&self) -> impl Iterator<Item = Posting> + '_ {
+        self.postings.iter().map(|posting| Posting {
+            amount: posting.amount.normalize(),
+            ..posting.clone()
+        })
+    }
+
+    pub fn transaction(&self, tx: TransactionId) -> Option<TransactionInfo> {
+        self.transactions
+            .get(&tx)
+            .filter(|executed| !executed.failed)
+            .map(AccountTransaction::info)
+    }
+
+    /// The executed transactions that the account still keeps: the disputed transactions that left
+    /// the dispute window by id, then the deposits and withdrawals and then the transfers, oldest first.
+    pub fn history(&self) -> Vec<TransactionInfo> {
+        let mut outside: Vec<_> = self
+            .transactions
+            .iter()
+            .filter(|(_, executed)| executed.outside_window)
+            .collect();
+        outside.sort_by_key(|(tx, _)| **tx);
+        let outside = outside.into_iter().map(|(_, executed)| executed.info());
+        let window = self.window.iter().chain(&self.others);
+        outside.chain(window.filter_map(|tx| self.transaction(*tx))).collect()
+    }
+
+    /// Determines the effect of a transaction, without applying it yet.
+    pub fn plan(&self, transaction: &Transaction, policy: &Policy) -> Result<Planned> {
         ensure!(self.client == transaction.client, "transaction is for this account");
-        if !self.locked {
-            match transaction.transaction_type {
-                Deposit => self.deposit(transaction)?,
-                Withdrawal => self.withdrawal(transaction)?,
-                Dispute => self.dispute(transaction)?,
-                Resolve => self.resolve(transaction)?,
-                Chargeback => self.chargeback(transaction)?,
-            }
+        if let Err(reason) = self.state.permits(transaction.transaction_type) {
+            return Ok(Err(reason));
         }
-        Ok(())
+        if transaction.timestamp.is_some() && transaction.timestamp < self.latest {
+            return Ok(Err(RejectionReason::TimestampNotMonotonic));
+        }
+        let planned = match transaction.transaction_type {
+            Deposit => self.deposit(transaction),
+            Withdrawal => self.withdrawal(transaction),
+            Dispute => self.dispute(transaction, policy),
+            Resolve => self.resolve(transaction, policy),
+            Chargeback => self.chargeback(transaction, policy),
+            Transfer => self.transfer(transaction),
+            Unlock => Ok(self.operation(AccountState::Active)),
+            Freeze => Ok(self.operation(AccountState::Frozen)),
+            Close => self.close(),
+        };
+        let planned = planned.and_then(|transition| self.charge(transaction, transition, policy));
+        Ok(planned.map(|mut transition| {
+            transition.latest = transaction.timestamp.or(self.latest);
+            transition
+        }))
     }
 
-    fn deposit(&mut self, transaction: Transaction) -> Result<()> {
-        let amount = &transaction.amount.context("Deposit requires amount")?;
+    pub fn apply(&mut self, transaction: Transaction, transition: Transition) {
+        let state = transition.state;
+        if let Some(fee) = &transition.fee {
+            self.fees
+                .entry(transition.asset.clone())
+                .or_default()
+                .saturating_add(fee);
+        }
+        self.update(transition);
         let tx = transaction.tx;
-        self.available += amount;
-        self.total += amount;
-        let state = TransactionState::executed();
-        self.transactions.insert(tx, AccountTransaction { transaction, state });
-        Ok(())
+        match transaction.transaction_type {
+            Deposit | Withdrawal | Transfer => {
+                let executed = AccountTransaction {
+                    transaction,
+                    state,
+                    outside_window: false,
+                    failed: false,
+                };
+                self.transactions.insert(tx, executed);
+            }
+            Dispute | Resolve | Chargeback => {
+                if let Some(disputed) = self.transactions.get_mut(&tx) {
+                    disputed.state = state;
+                    if disputed.outside_window && state.dispute != DisputeState::Disputed {
+                        self.transactions.remove(&tx);
+                        self.evict_postings();
+                    }
+                }
+            }
+            Unlock | Freeze | Close => {}
+        }
     }
 
-    fn withdrawal(&mut self, transaction: Transaction) -> Result<()> {
-        let amount = &transaction.amount.context("Withdrawal requires amount")?;
+    /// Keeps a withdrawal that was rejected for insufficient funds, without changing the balances.
+    fn fail(&mut self, transaction: Transaction) {
         let tx = transaction.tx;
-        let state = if &self.available >= amount {
-            self.available -= amount;
-            self.total -= amount;
-            TransactionState::executed()
-        } else {
-            TransactionState::Failed
+        let failed = AccountTransaction {
+            transaction,
+            state: TransactionState::executed(),
+            outside_window: false,
+            failed: true,
         };
-        self.transactions.insert(tx, AccountTransaction { transaction, state });
-        Ok(())
+        self.transactions.insert(tx, failed);
     }
 
-    fn dispute(&mut self, transaction: Transaction) -> Result<()> {
-        if let Some(AccountTransaction {
-            transaction:
-                Transaction {
-                    transaction_type: Deposit,
-                    amount: Some(amount),
-                    ..
-                },
-            state:
-                TransactionState::Executed {
-                    dispute: dispute @ (DisputeState::Undisputed | DisputeState::Resolved),
-                },
-        }) = self.transactions.get_mut(&transaction.tx)
-        {
-            *dispute = DisputeState::Disputed;
-            self.available -= amount;
-            self.held += amount;
+    /// Takes a slot for a transaction that the registry gave a new id, whether or not it is accepted,
+    /// in the same way as the registry does, and evicts the transactions that left their window.
+    fn claim(&mut self, transaction: &Transaction, registered: &Outcome, policy: &Policy) {
+        if registered.is_err() {
+            return;
         }
-        Ok(())
+        match transaction.transaction_type {
+            Deposit | Withdrawal => self.window.push_back(transaction.tx),
+            Transfer | Unlock | Freeze | Close => self.others.push_back(transaction.tx),
+            Dispute | Resolve | Chargeback => return,
+        }
+        self.evict(policy);
     }
 
-    fn resolve(&mut self, transaction: Transaction) -> Result<()> {
-        if let Some(AccountTransaction {
-            transaction:
-                Transaction {
-                    transaction_type: Deposit,
-                    amount: Some(amount),
-                    ..
-                },
-            state: TransactionState::Executed {
-                dispute: dispute @ DisputeState::Disputed,
-            },
-        }) = self.transactions.get_mut(&transaction.tx)
-        {
-            *dispute = DisputeState::Resolved;
-            self.available += amount;
-            self.held -= amount;
+    /// Whether the account still keeps the transaction, which may have left the dispute window.
+    fn keeps(&self, tx: TransactionId) -> bool {
+        self.transactions.contains_key(&tx)
+    }
+
+    /// Checks that the balances are consistent, describing the first inconsistency.
+    fn verify(&self) -> Result<(), String> {
+        let mut disputed: BTreeMap<&Asset, Amount> = BTreeMap::new();
+        for executed in self.transactions.values() {
+            if executed.state.dispute == DisputeState::Disputed {
+                let held = disputed.entry(&executed.transaction.asset).or_default();
+                held.try_add(&executed.state.disputed)
+                    .map_err(|reason| reason.to_string())?;
+            }
         }
+        for (asset, balance) in &self.balances {
+            let mut total = balance.available;
+            total.try_add(&balance.held).map_err(|reason| reason.to_string())?;
+            if total != balance.total {
+                return Err(format!(
+                    "available and held funds don't add up to the total in asset '{}'",
+                    asset
+                ));
+            }
+            if balance.held != disputed.remove(asset).unwrap_or_default() {
+                return Err(format!("held funds differ from the open disputes in asset '{}'", asset));
+            }
+        }
+        match disputed.into_iter().find(|(_, held)| *held != Amount::default()) {
+            Some((asset, _)) => Err(format!("open disputes without held funds in asset '{}'", asset)),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes over the balance of a transition, without recording a transaction.
+    /// A transition without postings, such as an operator action without a fee,
+    /// only changes the state, so that it doesn't add an empty balance.
+    fn update(&mut self, transition: Transition) {
+        if !transition.postings.is_empty() {
+            self.balances.insert(transition.asset, transition.balance);
+        }
+        self.postings.extend(transition.postings);
+        self.state = transition.account_state;
+        self.latest = transition.latest;
+    }
+
+    /// Evicts the transactions that left the dispute window, unless they are disputed.
+    fn evict(&mut self, policy: &Policy) {
+        let Some(window) = policy.dispute_window else {
+            return;
+        };
+        while self.window.len() > window {
+            let Some(tx) = self.window.pop_front() else {
+                break;
+            };
+            match self.transactions.get_mut(&tx) {
+                Some(disputed) if disputed.state.dispute == DisputeState::Disputed => disputed.outside_window = true,
+                _ => {
+                    self.transactions.remove(&tx);
+                }
+            }
+        }
+        while self.others.len() > window {
+            if let Some(tx) = self.others.pop_front() {
+                self.transactions.remove(&tx);
+            }
+        }
+        self.evict_postings();
+    }
+
+    /// Evicts the oldest postings until one belongs to a deposit or withdrawal that is still kept,
+    /// adding up what the house paid in them.
+    fn evict_postings(&mut self) {
+        while let Some(posting) = self.postings.front() {
+            let kept = self.transactions.get(&posting.tx);
+            if kept.is_some_and(|kept| kept.transaction.transaction_type != Transfer) {
+                break;
+            }
+            self.evicted
+                .entry(posting.asset.clone())
+                .or_default()
+                .saturating_add(&posting.paid());
+            self.postings.pop_front();
+        }
+    }
+
+    /// Starts a transition from the current balance of the account in an asset.
+    fn transition(&self, asset: &Asset, state: TransactionState) -> Transition {
+        Transition {
+            asset: asset.clone(),
+            balance: self.balances.get(asset).cloned().unwrap_or_default(),
+            account_state: self.state,
+            latest: self.latest,
+            state,
+            counterparty: None,
+            fee: None,
+            postings: Vec::new(),
+        }
+    }
+
+    /// Books a posting in the asset of the transition, which updates the balance it leads to.
+    fn post(
+        &self,
+        transition: &mut Transition,
+        tx: TransactionId,
+        debit: LedgerAccount,
+        credit: LedgerAccount,
+        amount: &Amount,
+    ) -> Outcome {
+        let posting = Posting {
+            tx,
+            asset: transition.asset.clone(),
+            debit,
+            credit,
+            amount: *amount,
+        };
+        transition.balance.post(self.client, &posting)?;
+        transition.postings.push(posting);
         Ok(())
     }
 
-    fn chargeback(&mut self, transaction: Transaction) -> Result<()> {
-        if let Some(AccountTransaction {
-            transaction:
-                Transaction {
-                    transaction_type: Deposit,
-                    amount: Some(amount),
-                    ..
-                },
-            state: TransactionState::Executed {
-                dispute: dispute @ DisputeState::Disputed,
-            },
-        }) = self.transactions.get_mut(&transaction.tx)
+    /// Charges the fee of a transaction to the available funds, in the asset of the transition.
+    /// Withdrawals and transfers are rejected if the funds don't cover the fee as well.
+    fn charge(&self, transaction: &Transaction, mut transition: Transition, policy: &Policy) -> Planned {
+        let Some(fee) = policy.fee(self.client, transaction.transaction_type) else {
+            return Ok(transition);
+        };
+        let fee = fee.of(transaction.amount)?;
+        if fee == Amount::default() {
+            return Ok(transition);
+        }
+        if matches!(transaction.transaction_type, Withdrawal | Transfer) && transition.balance.available < fee {
+            return Err(RejectionReason::InsufficientFunds);
+        }
+        self.post(&mut transition, transaction.tx, Available(self.client), Fees, &fee)?;
+        transition.fee = Some(fee);
+        Ok(transition)
+    }
+
+    fn deposit(&self, transaction: &Transaction) -> Planned {
+        let amount = &transaction.amount.ok_or(RejectionReason::MissingAmount)?;
+        let mut transition = self.transition(&transaction.asset, TransactionState::executed());
+        self.post(
+            &mut transition,
+            transaction.tx,
+            HouseCash,
+            Available(self.client),
+            amount,
+        )?;
+        Ok(transition)
+    }
+
+    fn withdrawal(&self, transaction: &Transaction) -> Planned {
+        let amount = &transaction.amount.ok_or(RejectionReason::MissingAmount)?;
+        let mut transition = self.transition(&transaction.asset, TransactionState::executed());
+        if &transition.balance.available < amount {
+            return Err(RejectionReason::InsufficientFunds);
+        }
+        self.post(
+            &mut transition,
+            transaction.tx,
+            Available(self.client),
+            HouseCash,
+            amount,
+        )?;
+        Ok(transition)
+    }
+
+    /// The sending side of a transfer is planned like a withdrawal to the counterparty.
+    fn transfer(&self, transaction: &Transaction) -> Planned {
+        let counterparty = match transaction.counterparty {
+            Some(counterparty) if counterparty != self.client => counterparty,
+            _ => return Err(RejectionReason::InvalidCounterparty),
+        };
+        let amount = &transaction.amount.ok_or(RejectionReason::MissingAmount)?;
+        let mut transition = self.transition(&transaction.asset, TransactionState::executed());
+        if &transition.balance.available < amount {
+            return Err(RejectionReason::InsufficientFunds);
+        }
+        self.post(
+            &mut transition,
+            transaction.tx,
+            Available(self.client),
+            Available(counterparty),
+            amount,
+        )?;
+        Ok(transition)
+    }
+
+    /// The receiving side of a transfer, which is not part of the history of this account.
+    fn credit(&self, transaction: &Transaction) -> Planned {
+        if matches!(self.state, AccountState::Locked | AccountState::Closed) {
+            return Err(RejectionReason::CounterpartyUnavailable);
+        }
+        let amount = &transaction.amount.ok_or(RejectionReason::MissingAmount)?;
+        let mut transition = self.transition(&transaction.asset, TransactionState::executed());
+        self.post(
+            &mut transition,
+            transaction.tx,
+            Available(transaction.client),
+            Available(self.client),
+            amount,
+        )?;
+        Ok(transition)
+    }
+
+    /// An operator action only changes the state of the account. Its transition is in the asset
+    /// of an existing balance, or in the default asset, which is where a fee is charged.
+    fn operation(&self, state: AccountState) -> Transition {
+        let asset = self.balances.keys().next().cloned().unwrap_or_default();
+        let mut transition = self.transition(&asset, TransactionState::executed());
+        transition.account_state = state;
+        transition
+    }
+
+    /// Only an account without any funds, including held funds, can be closed.
+    fn close(&self) -> Planned {
+        let empty = Balance::default();
+        if self
+            .balances
+            .values()
+            .any(|balance| balance.total != empty.total || balance.held != empty.held)
         {
-            *dispute = DisputeState::Chargeback;
-            self.locked = true;
-            self.held -= amount;
-            self.total -= amount;
+            return Err(RejectionReason::AccountNotEmpty);
+        }
+        Ok(self.operation(AccountState::Closed))
+    }
+
+    /// A disputed deposit holds the deposited funds until the dispute is settled.
+    /// A disputed withdrawal holds a provisional credit of the withdrawn funds instead.
+    ///
+    /// A dispute with an amount only disputes that part of the transaction, and further
+    /// partial disputes add to the open dispute. Without an amount, the rest is disputed.
+    /// After a partial chargeback, the part that wasn't charged back can still be disputed.
+    fn dispute(&self, transaction: &Transaction, policy: &Policy) -> Planned {
+        let disputed = self.disputable(transaction, policy)?;
+        let mut undisputed = disputed.amount;
+        undisputed.try_sub(&disputed.state.disputed)?;
+        undisputed.try_sub(&disputed.state.charged_back)?;
+        match disputed.state.dispute {
+            DisputeState::Chargeback if undisputed <= Amount::default() => Err(RejectionReason::InvalidDisputeState),
+            _ => {
+                let amount = &match transaction.amount {
+                    Some(amount) if amount > undisputed => return Err(RejectionReason::DisputedAmountTooLarge),
+                    Some(amount) => amount,
+                    None => undisputed,
+                };
+                if amount <= &Amount::default() {
+                    return Err(RejectionReason::InvalidDisputeState);
+                }
+                let mut disputes = disputed.state.disputes;
+                if disputed.state.dispute != DisputeState::Disputed {
+                    if policy.max_redisputes.is_some_and(|max| disputes > max) {
+                        return Err(RejectionReason::TooManyDisputes);
+                    }
+                    disputes += 1;
+                }
+                let disputed_at = transaction.timestamp.or(self.latest);
+                if let (Some(deadline), Some(Timestamp(executed)), Some(Timestamp(disputed_at))) =
+                    (policy.dispute_deadline_days, disputed.timestamp, disputed_at)
+                {
+                    if disputed_at.saturating_sub(executed) > deadline.saturating_mul(SECONDS_PER_DAY) {
+                        return Err(RejectionReason::DeadlineExceeded);
+                    }
+                }
+                let mut outstanding = disputed.state.disputed;
+                outstanding.try_add(amount)?;
+                let mut transition = self.transition(
+                    &disputed.asset,
+                    TransactionState {
+                        dispute: DisputeState::Disputed,
+                        disputes,
+                        disputed: outstanding,
+                        ..disputed.state
+                    },
+                );
+                let debit = match disputed.transaction_type {
+                    Withdrawal => ChargebackLosses,
+                    _ => {
+                        if !policy.allow_negative_available && &transition.balance.available < amount {
+                            return Err(RejectionReason::InsufficientFunds);
+                        }
+                        Available(self.client)
+                    }
+                };
+                self.post(&mut transition, transaction.tx, debit, Held(self.client), amount)?;
+                Ok(transition)
+            }
+        }
+    }
+
+    /// Resolving a dispute releases the disputed part of a held deposit back to the client,
+    /// or releases the provisional credit of a withdrawal.
+    fn resolve(&self, transaction: &Transaction, policy: &Policy) -> Planned {
+        let disputed = self.disputable(transaction, policy)?;
+        match disputed.state.dispute {
+            DisputeState::Disputed => {
+                let mut transition = self.transition(
+                    &disputed.asset,
+                    TransactionState {
+                        dispute: DisputeState::Resolved,
+                        disputed: Amount::default(),
+                        ..disputed.state
+                    },
+                );
+                let credit = match disputed.transaction_type {
+                    Withdrawal => ChargebackLosses,
+                    _ => Available(self.client),
+                };
+                let amount = &disputed.state.disputed;
+                self.post(&mut transition, transaction.tx, Held(self.client), credit, amount)?;
+                Ok(transition)
+            }
+            _ => Err(RejectionReason::InvalidDisputeState),
         }
-        Ok(())
     }
+
+    /// A chargeback reverses the disputed part of the transaction: A held deposit is
+    /// taken from the client, while the credit of a withdrawal becomes available.
+    /// The part that was charged back cannot be disputed any further afterwards.
+    fn chargeback(&self, transaction: &Transaction, policy: &Policy) -> Planned {
+        let disputed = self.disputable(transaction, policy)?;
+        match disputed.state.dispute {
+            DisputeState::Disputed => {
+                let mut charged_back = disputed.state.charged_back;
+                charged_back.try_add(&disputed.state.disputed)?;
+                let mut transition = self.transition(
+                    &disputed.asset,
+                    TransactionState {
+                        dispute: DisputeState::Chargeback,
+                        disputed: Amount::default(),
+                        charged_back,
+                        ..disputed.state
+                    },
+                );
+                let credit = match disputed.transaction_type {
+                    Withdrawal => Available(self.client),
+                    _ => HouseCash,
+                };
+                let amount = &disputed.state.disputed;
+                self.post(&mut transition, transaction.tx, Held(self.client), credit, amount)?;
+                if policy.lock_on_chargeback {
+                    transition.account_state = AccountState::Locked;
+                }
+                Ok(transition)
+            }
+            _ => Err(RejectionReason::InvalidDisputeState),
+        }
+    }
+
+    /// Looks up a transaction that can be disputed, which must be in the asset of the dispute if it has one.
+    fn disputable(&self, transaction: &Transaction, policy: &Policy) -> Result<Disputed, RejectionReason> {
+        let tx = transaction.tx;
+        let disputed = match self.transactions.get(&tx) {
+            None => Err(RejectionReason::UnknownTransaction),
+            Some(AccountTransaction { failed: true, .. }) => Err(RejectionReason::NotDisputable),
+            Some(AccountTransaction {
+                transaction:
+                    Transaction {
+                        transaction_type,
+                        amount: Some(amount),
+                        timestamp,
+                        asset,
+                        ..
+                    },
+                state,
+                ..
+            }) if policy.disputable.contains(transaction_type) => Ok(Disputed {
+                transaction_type: *transaction_type,
+                amount: *amount,
+                timestamp: *timestamp,
+                asset: asset.clone(),
+                state: *state,
+            }),
+            Some(_) => Err(RejectionReason::NotDisputable),
+        }?;
+        if transaction.asset != Asset::default() && transaction.asset != disputed.asset {
+            return Err(RejectionReason::AssetMismatch);
+        }
+        Ok(disputed)
+    }
+}
+
+/// A transaction of the account history that can be disputed.
+struct Disputed {
+    transaction_type: TransactionType,
+    amount: Amount,
+    timestamp: Option<Timestamp>,
+    asset: Asset,
+    state: TransactionState,
 }
@@ -0,0 +1,209 @@
+use crate::binary;
+use crate::binary::BinaryReader;
+use crate::engine::Transaction;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+#[derive(ValueEnum, Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Format {
+    /// Comma separated values with a header row
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+}
+
+/// The formats transactions can be read from, which include the binary format.
+#[derive(ValueEnum, Eq, PartialEq, Copy, Clone, Debug)]
+pub enum InputFormat {
+    /// Comma separated values with a header row
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+    /// Fixed-width records, as written by the convert command
+    Binary,
+}
+
+impl InputFormat {
+    /// The format of the rejected transactions, which are reported as CSV for binary input.
+    pub fn report_format(self) -> Format {
+        match self {
+            Self::Csv | Self::Binary => Format::Csv,
+            Self::Ndjson => Format::Ndjson,
+        }
+    }
+}
+
+/// A row of the input, kept as `Record` for reporting.
+pub enum Row {
+    Parsed(Record, Transaction),
+    /// The row could be read, but isn't a valid transaction
+    Invalid(Record, anyhow::Error),
+}
+
+/// The original row, with its line number for reporting.
+pub enum Record {
+    /// A CSV row, or a JSON row as a record with the whole line as its only field
+    Text(csv::StringRecord),
+    /// A binary record with its number, which is only turned into text if it's reported
+    Binary(u64, [u8; binary::RECORD_SIZE]),
+}
+
+impl Record {
+    pub fn line(&self) -> u64 {
+        match self {
+            Self::Text(record) => record.position().map_or(0, |position| position.line()),
+            Self::Binary(line, _) => *line,
+        }
+    }
+
+    /// The fields of the row as text.
+    pub fn fields(&self) -> Cow<'_, csv::StringRecord> {
+        match self {
+            Self::Text(record) => Cow::Borrowed(record),
+            Self::Binary(_, bytes) => Cow::Owned(binary::text(bytes)),
+        }
+    }
+}
+
+/// Reads transactions row by row, in either format.
+pub enum Input<R: Read> {
+    Csv {
+        reader: csv::Reader<R>,
+        headers: csv::StringRecord,
+    },
+    Ndjson {
+        reader: BufReader<R>,
+        line: u64,
+    },
+    Binary(BinaryReader<R>),
+}
+
+impl<R: Read> Input<R> {
+    pub fn new(format: InputFormat, reader: R) -> Result<Self> {
+        Ok(match format {
+            InputFormat::Csv => {
+                let mut reader = csv::ReaderBuilder::new()
+                    .flexible(true)
+                    .trim(csv::Trim::All)
+                    .from_reader(reader);
+                let headers = reader.headers()?.clone();
+                Self::Csv { reader, headers }
+            }
+            InputFormat::Ndjson => Self::Ndjson {
+                reader: BufReader::new(reader),
+                line: 0,
+            },
+            InputFormat::Binary => Self::Binary(BinaryReader::new(reader)?),
+        })
+    }
+
+    /// The column names of CSV input, or of the rows reported for binary input.
+    /// JSON input doesn't have any.
+    pub fn headers(&self) -> csv::StringRecord {
+        match self {
+            Self::Csv { headers, .. } => headers.clone(),
+            Self::Ndjson { .. } => csv::StringRecord::new(),
+            Self::Binary(_) => csv::StringRecord::from(&binary::COLUMNS[..]),
+        }
+    }
+
+    /// Reads the next row, failing only if the input itself can no longer be read.
+    pub fn next(&mut self) -> Result<Option<Row>> {
+        match self {
+            Self::Csv { reader, headers } => {
+                let mut bytes = csv::ByteRecord::new();
+                if !reader.read_byte_record(&mut bytes)? {
+                    return Ok(None);
+                }
+                let position = bytes.position().cloned();
+                Ok(Some(match csv::StringRecord::from_byte_record(bytes) {
+                    Ok(record) => match record.deserialize(Some(headers)) {
+                        Ok(transaction) => Row::Parsed(Record::Text(record), transaction),
+                        Err(error) => Row::Invalid(Record::Text(record), error.into()),
+                    },
+                    // The row is reported with the invalid bytes replaced
+                    Err(error) => {
+                        let mut record = csv::StringRecord::from_byte_record_lossy(error.into_byte_record());
+                        record.set_position(position);
+                        let line = record.position().map_or(0, |position| position.line());
+                        Row::Invalid(Record::Text(record), anyhow!("invalid UTF-8 on line {}", line))
+                    }
+                }))
+            }
+            Self::Ndjson { reader, line } => loop {
+                let mut bytes = Vec::new();
+                if reader.read_until(b'\n', &mut bytes)? == 0 {
+                    return Ok(None);
+                }
+                *line += 1;
+                let text = String::from_utf8_lossy(&bytes);
+                let text = text.trim();
+                if text.is_empty() {
+                    continue;
+                }
+                let mut position = csv::Position::new();
+                position.set_line(*line);
+                let mut record = csv::StringRecord::from(vec![text]);
+                record.set_position(Some(position));
+                let record = Record::Text(record);
+                return Ok(Some(match from_json(&bytes) {
+                    Ok(transaction) => Row::Parsed(record, transaction),
+                    Err(error) => Row::Invalid(record, anyhow!("invalid JSON on line {}: {}", line, error)),
+                }));
+            },
+            Self::Binary(reader) => reader.next(),
+        }
+    }
+}
+
+/// Parses a transaction from JSON, with the amount as a string or as a number.
+/// A number is taken exactly as written, rather than as a floating point number.
+pub fn from_json(bytes: &[u8]) -> serde_json::Result<Transaction> {
+    serde_json::from_slice(bytes).or_else(|error| {
+        let mut value: serde_json::Value = serde_json::from_slice(bytes)?;
+        match value.get_mut("amount") {
+            Some(amount @ serde_json::Value::Number(_)) => {
+                *amount = serde_json::Value::String(amount.to_string());
+                serde_json::from_value(value)
+            }
+            _ => Err(error),
+        }
+    })
+}
+
+/// Writes reports, such as the accounts, in either format.
+pub enum Output<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    Ndjson(BufWriter<W>),
+}
+
+impl<W: Write> Output<W> {
+    pub fn new(format: Format, writer: W) -> Self {
+        match format {
+            Format::Csv => Self::Csv(Box::new(csv::Writer::from_writer(writer))),
+            Format::Ndjson => Self::Ndjson(BufWriter::new(writer)),
+        }
+    }
+
+    pub fn serialize(&mut self, value: impl Serialize) -> Result<()> {
+        match self {
+            Self::Csv(writer) => writer.serialize(value)?,
+            Self::Ndjson(writer) => {
+                serde_json::to_writer(&mut *writer, &value)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        match self {
+            Self::Csv(writer) => writer.flush()?,
+            Self::Ndjson(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+use crate::engine::{AccountInfo, Asset, ClientId, Engine, Posting, TransactionId, TransactionInfo};
+use crate::format;
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::task;
+
+/// The number of accounts on a page if the request doesn't specify it
+const DEFAULT_LIMIT: usize = 100;
+
+/// The largest number of accounts on a page, to which larger limits are reduced
+const MAX_LIMIT: usize = 1000;
+
+type Error = (StatusCode, String);
+
+type Response<T> = Result<Json<T>, Error>;
+
+/// Serves a JSON API over HTTP on the listener:
+/// - `POST /transactions` handles the transaction in the body, unless it's an operator action
+/// - `GET /accounts/{client}[?asset=<asset>]` returns the balances of a client in all its assets, or in the asset
+/// - `GET /accounts/{client}/postings` returns the postings of the ledger accounts of a client
+/// - `GET /accounts[?offset=<offset>&limit=<limit>]` returns a page of the accounts
+/// - `GET /transactions/{tx}` returns an executed transaction and the state of its disputes
+///
+/// The engine is only accessed on blocking threads, as handling a transaction
+/// may wait for the journal to be written to disk.
+pub async fn serve(engine: Arc<Mutex<Engine>>, listener: TcpListener) -> std::io::Result<()> {
+    axum::serve(listener, router(engine)).await
+}
+
+fn router(engine: Arc<Mutex<Engine>>) -> Router {
+    Router::new()
+        .route("/transactions", post(submit))
+        .route("/transactions/:tx", get(transaction))
+        .route("/accounts", get(accounts))
+        .route("/accounts/:client", get(account))
+        .route("/accounts/:client/postings", get(postings))
+        .with_state(engine)
+}
+
+/// The outcome of a submitted transaction, with the reason if it was rejected.
+#[derive(Serialize)]
+struct Submitted {
+    accepted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+async fn submit(State(engine): State<Arc<Mutex<Engine>>>, body: Bytes) -> Response<Submitted> {
+    let transaction =
+        format::from_json(&body).map_err(|error| (StatusCode::UNPROCESSABLE_ENTITY, error.to_string()))?;
+    let outcome = with_engine(engine, move |engine| {
+        engine
+            .handle_partner(transaction)
+            .map_err(|error| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", error)))
+    })
+    .await?;
+    Ok(match outcome {
+        Ok(()) => Json(Submitted {
+            accepted: true,
+            reason: None,
+        }),
+        Err(reason) => Json(Submitted {
+            accepted: false,
+            reason: Some(reason.to_string()),
+        }),
+    })
+}
+
+#[derive(Deserialize)]
+struct AssetQuery {
+    asset: Option<Asset>,
+}
+
+async fn account(
+    State(engine): State<Arc<Mutex<Engine>>>,
+    Path(client): Path<ClientId>,
+    Query(query): Query<AssetQuery>,
+) -> Response<Vec<AccountInfo>> {
+    with_engine(engine, move |engine| {
+        let account = engine.account(client).ok_or_else(|| not_found("unknown client"))?;
+        match query.asset {
+            Some(asset) => {
+                let info = account.balance(&asset).ok_or_else(|| not_found("unknown asset"))?;
+                Ok(Json(vec![info]))
+            }
+            None => Ok(Json(account.infos())),
+        }
+    })
+    .await
+}
+
+async fn postings(State(engine): State<Arc<Mutex<Engine>>>, Path(client): Path<ClientId>) -> Response<Vec<Posting>> {
+    with_engine(engine, move |engine| {
+        let account = engine.account(client).ok_or_else(|| not_found("unknown client"))?;
+        Ok(Json(account.postings().collect()))
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+struct Page {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// A page of accounts with the balances in all their assets.
+#[derive(Serialize)]
+struct Accounts {
+    accounts: Vec<AccountInfo>,
+    /// The offset of the next page, if there are more accounts
+    next: Option<usize>,
+}
+
+async fn accounts(State(engine): State<Arc<Mutex<Engine>>>, Query(page): Query<Page>) -> Response<Accounts> {
+    // A client following the next page would never get any further without accounts on a page
+    let limit = match page.limit {
+        Some(0) => return Err((StatusCode::BAD_REQUEST, "limit must be positive".to_string())),
+        limit => limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT),
+    };
+    with_engine(engine, move |engine| {
+        let mut accounts = engine.accounts().skip(page.offset);
+        let infos = accounts
+            .by_ref()
+            .take(limit)
+            .flat_map(|account| account.infos())
+            .collect();
+        let next = accounts.next().map(|_| page.offset.saturating_add(limit));
+        Ok(Json(Accounts { accounts: infos, next }))
+    })
+    .await
+}
+
+async fn transaction(
+    State(engine): State<Arc<Mutex<Engine>>>,
+    Path(tx): Path<TransactionId>,
+) -> Response<TransactionInfo> {
+    with_engine(engine, move |engine| {
+        let info = engine.transaction(tx).ok_or_else(|| not_found("unknown transaction"))?;
+        Ok(Json(info))
+    })
+    .await
+}
+
+/// Runs the function on the locked engine on a blocking thread.
+async fn with_engine<T: Send + 'static>(
+    engine: Arc<Mutex<Engine>>,
+    function: impl FnOnce(&mut Engine) -> Result<T, Error> + Send + 'static,
+) -> Result<T, Error> {
+    task::spawn_blocking(move || {
+        let mut engine = engine
+            .lock()
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "engine poisoned".to_string()))?;
+        function(&mut engine)
+    })
+    .await
+    .map_err(|error| (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?
+}
+
+fn not_found(message: &str) -> Error {
+    (StatusCode::NOT_FOUND, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Policy;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn serves_accounts_and_transactions() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let engine = Arc::new(Mutex::new(Engine::new(Policy::default())));
+        tokio::spawn(serve(engine, listener));
+
+        let submitted = [
+            (
+                r#"{"type":"deposit","client":1,"tx":1,"amount":"2.0"}"#,
+                r#"{"accepted":true}"#,
+            ),
+            (
+                r#"{"type":"withdrawal","client":1,"tx":2,"amount":"3.0"}"#,
+                r#"{"accepted":false,"reason":"insufficient funds"}"#,
+            ),
+            (
+                r#"{"type":"deposit","client":2,"tx":3,"amount":1}"#,
+                r#"{"accepted":true}"#,
+            ),
+            (r#"{"type":"dispute","client":1,"tx":1}"#, r#"{"accepted":true}"#),
+            (
+                r#"{"type":"freeze","client":2,"tx":4}"#,
+                r#"{"accepted":false,"reason":"operator action not allowed"}"#,
+            ),
+            (
+                r#"{"type":"deposit","client":3,"tx":5,"amount":"1","asset":"BTC"}"#,
+                r#"{"accepted":true}"#,
+            ),
+        ];
+        for (transaction, reply) in submitted {
+            assert_eq!(
+                request(address, "POST", "/transactions", transaction).await,
+                (200, reply.to_string())
+            );
+        }
+        let (status, _) = request(address, "POST", "/transactions", r#"{"type":"deposit"}"#).await;
+        assert_eq!(status, 422);
+
+        assert_eq!(
+            request(address, "GET", "/accounts/1", "").await,
+            (
+                200,
+                r#"[{"client":1,"asset":"","available":"0","held":"2","total":"2","locked":false,"state":"active"}]"#
+                    .to_string()
+            )
+        );
+        assert_eq!(request(address, "GET", "/accounts/4", "").await.0, 404);
+        // A client with funds in another asset only has no balance in the default asset
+        assert_eq!(
+            request(address, "GET", "/accounts/3", "").await,
+            (
+                200,
+                r#"[{"client":3,"asset":"BTC","available":"1","held":"0","total":"1","locked":false,"state":"active"}]"#
+                    .to_string()
+            )
+        );
+        assert_eq!(request(address, "GET", "/accounts/3?asset=BTC", "").await.0, 200);
+        assert_eq!(request(address, "GET", "/accounts/3?asset=", "").await.0, 404);
+        assert_eq!(
+            request(address, "GET", "/accounts/1/postings", "").await,
+            (
+                200,
+                r#"[{"tx":1,"asset":"","debit":"house:cash","credit":"available:1","amount":"2"},{"tx":1,"asset":"","debit":"available:1","credit":"held:1","amount":"2"}]"#
+                    .to_string()
+            )
+        );
+
+        let (status, page) = request(address, "GET", "/accounts?limit=1", "").await;
+        assert_eq!(status, 200);
+        let page: serde_json::Value = serde_json::from_str(&page).unwrap();
+        assert_eq!(page["accounts"][0]["client"], 1);
+        assert_eq!(page["next"], 1);
+        let (_, page) = request(address, "GET", "/accounts?offset=1&limit=1", "").await;
+        let page: serde_json::Value = serde_json::from_str(&page).unwrap();
+        assert_eq!(page["accounts"][0]["client"], 2);
+        assert_eq!(page["next"], 2);
+        assert_eq!(request(address, "GET", "/accounts?limit=0", "").await.0, 400);
+        let (_, page) = request(address, "GET", "/accounts?limit=1000000", "").await;
+        let page: serde_json::Value = serde_json::from_str(&page).unwrap();
+        assert_eq!(page["accounts"].as_array().unwrap().len(), 3);
+
+        let (status, transaction) = request(address, "GET", "/transactions/1", "").await;
+        assert_eq!(status, 200);
+        let transaction: serde_json::Value = serde_json::from_str(&transaction).unwrap();
+        assert_eq!(transaction["type"], "deposit");
+        assert_eq!(transaction["dispute"], "Disputed");
+        assert_eq!(transaction["disputed"], "2");
+        assert_eq!(request(address, "GET", "/transactions/2", "").await.0, 404);
+    }
+
+    /// Sends a request on a new connection and returns the status and body of the response.
+    async fn request(address: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head[9..12].parse().unwrap(), body.to_string())
+    }
+}
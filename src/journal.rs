@@ -0,0 +1,74 @@
+use crate::engine::{Engine, Journal, Planned, Transaction};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// A line of the journal
+#[derive(Serialize, Deserialize)]
+struct Entry<T, P> {
+    transaction: T,
+    planned: P,
+}
+
+/// Appends the entries as JSON lines. Every entry is synced to disk before the transaction
+/// is applied, so that even a crash of the machine loses at most the transaction being handled.
+pub struct JournalWriter {
+    writer: BufWriter<File>,
+}
+
+impl JournalWriter {
+    pub fn new(file: File) -> Self {
+        Self {
+            writer: BufWriter::new(file),
+        }
+    }
+}
+
+impl Journal for JournalWriter {
+    fn record(&mut self, transaction: &Transaction, planned: &Planned) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, &Entry { transaction, planned })?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+pub fn open(path: &Path) -> Result<JournalWriter> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open journal {}", path.display()))?;
+    Ok(JournalWriter::new(file))
+}
+
+/// Applies the journaled transitions to the engine, which has to be in the state the journal started from.
+///
+/// A crash can leave the last entry incomplete, in which case it is skipped,
+/// as its transaction has never been applied.
+pub fn replay<R: BufRead>(engine: &mut Engine, reader: R) -> Result<()> {
+    let mut lines = reader.lines().enumerate().peekable();
+    while let Some((index, line)) = lines.next() {
+        let line = line?;
+        let entry: Entry<Transaction, Planned> = match serde_json::from_str(&line) {
+            Ok(entry) => entry,
+            Err(error) if lines.peek().is_none() => {
+                eprintln!("Skipping incomplete last journal entry: {}", error);
+                break;
+            }
+            Err(error) => return Err(error).with_context(|| format!("invalid journal entry on line {}", index + 1)),
+        };
+        engine
+            .replay(entry.transaction, entry.planned)
+            .with_context(|| format!("failed to replay journal entry on line {}", index + 1))?;
+    }
+    Ok(())
+}
+
+pub fn load(engine: &mut Engine, path: &Path) -> Result<()> {
+    let reader = BufReader::new(File::open(path)?);
+    replay(engine, reader).with_context(|| format!("failed to replay journal {}", path.display()))
+}
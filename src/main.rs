@@ -1,52 +1,410 @@
-use anyhow::Result;
-use clap::Parser;
-use engine::{Account, Transaction};
-use std::collections::BTreeMap;
-use std::io;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use anyhow::{anyhow, ensure, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use engine::{Account, Engine, Policy};
+use format::{Format, Input, InputFormat, Output, Record, Row};
+use repl::Repl;
+use serde::Serialize;
+use std::fmt::Display;
+use std::fs;
+use std::fs::File;
+use std::io::{IsTerminal, Read, Write};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{io, iter};
+use workers::{Job, Workers};
 
+mod binary;
 mod engine;
+mod format;
+mod http;
+mod journal;
+mod repl;
+mod server;
+mod snapshot;
+mod workers;
 
 #[derive(Parser, Debug)]
-#[clap(author = "Simon Adameit")]
+#[clap(
+    author = "Simon Adameit",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    transaction_csv: PathBuf,
+    #[clap(required = true)]
+    transactions: Option<PathBuf>,
+    /// The format of the transactions, which is also used for the rejected transactions
+    /// except that these are reported as CSV for binary input
+    #[clap(long, value_enum, default_value_t = InputFormat::Csv, global = true)]
+    input_format: InputFormat,
+    /// The format of the reported accounts and fees
+    #[clap(long, value_enum, default_value_t = Format::Csv, global = true)]
+    output_format: Format,
+    /// Write rejected transactions with their line number and reason to this file
+    #[clap(long, value_name = "FILE")]
+    rejected: Option<PathBuf>,
+    /// How to handle rows that cannot be parsed
+    #[clap(long, value_enum, default_value_t = Mode::Strict)]
+    mode: Mode,
+    /// Read the dispute policy from this TOML file
+    #[clap(long, value_name = "TOML", global = true)]
+    policy: Option<PathBuf>,
+    /// Start from the engine state saved in this snapshot file
+    #[clap(long, value_name = "SNAPSHOT", global = true)]
+    snapshot: Option<PathBuf>,
+    /// Save the engine state to this snapshot file when done
+    #[clap(long, value_name = "SNAPSHOT", global = true)]
+    save_snapshot: Option<PathBuf>,
+    /// Append every handled transaction with its effect to this journal file before applying it
+    #[clap(long, value_name = "JOURNAL", global = true)]
+    journal: Option<PathBuf>,
+    /// Write the fees collected from each client and by the house to this file when done
+    #[clap(long, value_name = "FILE", global = true)]
+    fees: Option<PathBuf>,
+    /// Verify the consistency of the accounts after every transaction, and abort on the first inconsistency
+    #[clap(long, global = true)]
+    check: bool,
+    /// Write the ledger postings of each client to this file when done
+    #[clap(long, value_name = "FILE", global = true)]
+    postings: Option<PathBuf>,
+    /// Handle the accounts on this many threads, sharded by client
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    workers: u16,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Accept transactions as CSV rows over TCP on localhost, and report the accounts on Ctrl-C
+    Serve {
+        /// The port to listen on
+        #[clap(long, default_value_t = 7878)]
+        port: u16,
+        /// Serve a JSON API over HTTP instead of CSV rows
+        #[clap(long)]
+        http: bool,
+    },
+    /// Rebuild the accounts from a journal, starting from the snapshot the journal started from
+    Replay {
+        #[clap(value_name = "JOURNAL")]
+        replayed: PathBuf,
+    },
+    /// Enter transactions and inspect the accounts interactively, one line at a time
+    Repl,
+    /// Convert transactions to the binary format, which is faster to read
+    Convert {
+        /// The transactions in the input format
+        #[clap(value_name = "TRANSACTIONS")]
+        converted: PathBuf,
+        /// The binary file to write
+        #[clap(value_name = "BINARY")]
+        binary: PathBuf,
+    },
+}
+
+#[derive(ValueEnum, Eq, PartialEq, Copy, Clone, Debug)]
+enum Mode {
+    /// Abort on the first invalid row
+    Strict,
+    /// Report invalid rows and continue with the next one
+    Lenient,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
-    let input = csv::ReaderBuilder::new()
-        .flexible(true)
-        .trim(csv::Trim::All)
-        .from_path(args.transaction_csv)?;
-    let output = csv::Writer::from_writer(io::stdout());
-    run(input, output)
+    let policy = match args.policy {
+        Some(path) => load_policy(&path).with_context(|| format!("failed to load policy {}", path.display()))?,
+        None => Policy::default(),
+    };
+    let mut engine = match &args.snapshot {
+        Some(path) => snapshot::load(path, policy.clone())?,
+        None => Engine::new(policy.clone()),
+    };
+    if args.check {
+        engine.enable_checks()?;
+    }
+    if let Some(path) = &args.journal {
+        // The workers handle the transactions in a different order than the journal would record them
+        ensure!(args.workers == 1, "--journal cannot be combined with --workers");
+        engine.set_journal(Box::new(journal::open(path)?));
+    }
+    let output = Output::new(args.output_format, io::stdout());
+    let engine = match args.command {
+        Some(Command::Serve { port, http }) => serve(engine, port, http, output)?,
+        Some(Command::Replay { replayed }) => {
+            journal::load(&mut engine, &replayed)?;
+            write_accounts(&engine, output)?;
+            engine
+        }
+        Some(Command::Repl) => {
+            let stdin = io::stdin();
+            let prompt = stdin.is_terminal();
+            Repl::new(engine, policy, args.output_format)?.run(stdin.lock(), io::stdout(), prompt)?
+        }
+        Some(Command::Convert { converted, binary }) => {
+            let input = Input::new(args.input_format, File::open(converted)?)?;
+            let count = binary::convert(input, File::create(binary)?)?;
+            eprintln!("Converted {} transactions", count);
+            engine
+        }
+        None => batch(
+            engine,
+            args.transactions,
+            args.input_format,
+            args.rejected,
+            args.mode,
+            args.workers,
+            output,
+        )?,
+    };
+    if let Some(path) = &args.fees {
+        write_fees(&engine, Output::new(args.output_format, File::create(path)?))?;
+    }
+    if let Some(path) = &args.postings {
+        write_postings(&engine, Output::new(args.output_format, File::create(path)?))?;
+    }
+    if let Some(path) = &args.save_snapshot {
+        snapshot::save(&engine, path).with_context(|| format!("failed to save snapshot {}", path.display()))?;
+    }
+    Ok(())
 }
 
-fn run<In, Out>(mut input: csv::Reader<In>, mut output: csv::Writer<Out>) -> Result<()>
+fn batch<Out: Write>(
+    engine: Engine,
+    transactions: Option<PathBuf>,
+    format: InputFormat,
+    rejected: Option<PathBuf>,
+    mode: Mode,
+    workers: u16,
+    output: Output<Out>,
+) -> Result<Engine> {
+    let input = Input::new(format, File::open(transactions.context("missing transactions")?)?)?;
+    let rejected: Box<dyn Write> = match rejected {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::sink()),
+    };
+    run(
+        engine,
+        input,
+        output,
+        Output::new(format.report_format(), rejected),
+        mode,
+        workers.into(),
+    )
+}
+
+fn serve<Out: Write>(engine: Engine, port: u16, http: bool, output: Output<Out>) -> Result<Engine> {
+    let engine = Arc::new(Mutex::new(engine));
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+        let served = async {
+            if http {
+                http::serve(engine.clone(), listener).await
+            } else {
+                server::serve(engine.clone(), listener).await;
+                Ok(())
+            }
+        };
+        tokio::select! {
+            served = served => served?,
+            signal = tokio::signal::ctrl_c() => signal?,
+        }
+        anyhow::Ok(())
+    })?;
+    // Stopping the runtime drops the connections, which release the engine
+    drop(runtime);
+    let engine = Arc::try_unwrap(engine).map_err(|_| anyhow!("engine still in use"))?;
+    let engine = engine.into_inner().map_err(|_| anyhow!("engine poisoned"))?;
+    write_accounts(&engine, output)?;
+    Ok(engine)
+}
+
+fn load_policy(path: &Path) -> Result<Policy> {
+    let policy: Policy = toml::from_str(&fs::read_to_string(path)?)?;
+    policy.validate()?;
+    Ok(policy)
+}
+
+fn run<In, Out, Rej>(
+    engine: Engine,
+    mut input: Input<In>,
+    output: Output<Out>,
+    rejected: Output<Rej>,
+    mode: Mode,
+    workers: usize,
+) -> Result<Engine>
 where
     In: Read,
     Out: Write,
+    Rej: Write,
 {
-    // We sort the accounts by client id for more predictable output
-    let mut accounts = BTreeMap::new();
-    for maybe_transaction in input.deserialize() {
-        let transaction: Transaction = maybe_transaction?;
-        let client = transaction.client;
-        let account = accounts.entry(client).or_insert_with(|| Account::new(client));
-        account.handle(transaction)?;
+    let mut report = Report::new(rejected, &input.headers(), workers > 1)?;
+    let mut processor = if workers > 1 {
+        Processor::Workers(Workers::spawn(engine, workers))
+    } else {
+        Processor::Engine(Box::new(engine))
+    };
+    while let Some(row) = input.next()? {
+        let (record, transaction) = match row {
+            Row::Parsed(record, transaction) => (record, transaction),
+            Row::Invalid(record, error) => {
+                let error = tolerate(mode, error)?;
+                report.reject(record, error)?;
+                continue;
+            }
+        };
+        match &mut processor {
+            Processor::Engine(engine) => {
+                let outcome = engine
+                    .handle(transaction)
+                    .with_context(|| format!("failed to handle transaction on line {}", record.line()))?;
+                if let Err(reason) = outcome {
+                    report.reject(record, reason)?;
+                }
+            }
+            Processor::Workers(workers) => workers.handle(Job { record, transaction })?,
+        }
     }
-    for account in accounts.values() {
-        output.serialize(account.info())?;
+    let engine = match processor {
+        Processor::Engine(engine) => *engine,
+        Processor::Workers(workers) => {
+            let (engine, rejections) = workers.finish()?;
+            for (record, reason) in rejections {
+                report.reject(record, reason)?;
+            }
+            engine
+        }
+    };
+    report.finish()?;
+    write_accounts(&engine, output)?;
+    Ok(engine)
+}
+
+fn write_accounts<Out: Write>(engine: &Engine, mut output: Output<Out>) -> Result<()> {
+    for account in engine.accounts() {
+        for info in account.infos() {
+            output.serialize(info)?;
+        }
+    }
+    output.flush()
+}
+
+/// Handles the transactions on this thread, or on worker threads.
+enum Processor {
+    Engine(Box<Engine>),
+    Workers(Workers),
+}
+
+/// The house collects the fees of all clients, and is reported without a client.
+fn write_fees<Out: Write>(engine: &Engine, mut output: Output<Out>) -> Result<()> {
+    for info in engine.fees() {
+        output.serialize(info)?;
+    }
+    output.flush()
+}
+
+/// The postings are reported client by client, so a transfer is reported for both clients.
+fn write_postings<Out: Write>(engine: &Engine, mut output: Output<Out>) -> Result<()> {
+    for posting in engine.accounts().flat_map(Account::postings) {
+        output.serialize(posting)?;
+    }
+    output.flush()
+}
+
+/// Fails with the error in strict mode, and leaves it to the report of rejected rows in lenient mode.
+fn tolerate(mode: Mode, error: anyhow::Error) -> Result<anyhow::Error> {
+    match mode {
+        // The error contains the exact position of the row
+        Mode::Strict => Err(error),
+        Mode::Lenient => Ok(error),
+    }
+}
+
+/// Reports input rows with their original columns, line number and the reason they had no effect.
+///
+/// The workers report their rejections only when they are finished,
+/// so in that case all rejections are sorted by line before writing them.
+struct Report<Rej: Write> {
+    writer: Output<Rej>,
+    columns: usize,
+    deferred: Option<Vec<(Record, String)>>,
+}
+
+/// A rejected JSON row, which is kept as it is if it isn't valid JSON
+#[derive(Serialize)]
+struct Rejected {
+    line: u64,
+    reason: String,
+    row: serde_json::Value,
+}
+
+impl<Rej: Write> Report<Rej> {
+    fn new(mut writer: Output<Rej>, headers: &csv::StringRecord, deferred: bool) -> Result<Self> {
+        if let Output::Csv(writer) = &mut writer {
+            writer.write_record(headers.iter().chain(["line", "reason"]))?;
+        }
+        Ok(Self {
+            writer,
+            columns: headers.len(),
+            deferred: deferred.then(Vec::new),
+        })
+    }
+
+    fn reject(&mut self, record: Record, reason: impl Display) -> Result<()> {
+        match &mut self.deferred {
+            Some(deferred) => deferred.push((record, reason.to_string())),
+            None => self.write(&record, reason)?,
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if let Some(mut deferred) = self.deferred.take() {
+            deferred.sort_by_key(|(record, _)| record.line());
+            for (record, reason) in deferred {
+                self.write(&record, reason)?;
+            }
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn write(&mut self, record: &Record, reason: impl Display) -> Result<()> {
+        let line = record.line();
+        let record = record.fields();
+        let Output::Csv(writer) = &mut self.writer else {
+            let text = record.get(0).unwrap_or_default();
+            let row = serde_json::from_str(text).unwrap_or_else(|_| serde_json::Value::from(text));
+            return self.writer.serialize(Rejected {
+                line,
+                reason: reason.to_string(),
+                row,
+            });
+        };
+        // Rows can have fewer columns than the header, as the input is flexible
+        for field in record.iter().chain(iter::repeat("")).take(self.columns) {
+            writer.write_field(field)?;
+        }
+        writer.write_field(line.to_string())?;
+        writer.write_field(reason.to_string())?;
+        writer.write_record(None::<&[u8]>)?;
+        Ok(())
     }
-    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::str;
+    use engine::Outcome;
+    use std::{env, process};
+
+    #[test]
+    fn command_line() {
+        use clap::CommandFactory;
+        Args::command().debug_assert();
+    }
 
     #[test]
     fn withdraw_and_deposit() {
@@ -59,9 +417,9 @@ deposit,      1,   3,     2.0
 withdrawal,   1,   4,     1.5
 withdrawal,   2,   5,     3.0",
             "\
-client,available,held,total,locked
-1,1.5,0,1.5,false
-2,2,0,2,false
+client,asset,available,held,total,locked,state
+1,,1.5,0,1.5,false,active
+2,,2,0,2,false,active
 ",
         )
     }
@@ -75,8 +433,8 @@ deposit,      1,   1,     1.0
 dispute,      1,   1
 ",
             "\
-client,available,held,total,locked
-1,0,1,1,false
+client,asset,available,held,total,locked,state
+1,,0,1,1,false,active
 ",
         )
     }
@@ -91,8 +449,8 @@ dispute,      1,   1
 resolve,      1,   1
 ",
             "\
-client,available,held,total,locked
-1,1,0,1,false
+client,asset,available,held,total,locked,state
+1,,1,0,1,false,active
 ",
         )
     }
@@ -107,8 +465,58 @@ dispute,      1,   1
 chargeback,   1,   1
 ",
             "\
-client,available,held,total,locked
-1,0,0,0,true
+client,asset,available,held,total,locked,state
+1,,0,0,0,true,locked
+",
+        )
+    }
+
+    #[test]
+    fn withdrawal_dispute() {
+        assert_result(
+            "\
+type,    client,  tx,  amount
+deposit,      1,   1,     3.0
+withdrawal,   1,   2,     1.0
+dispute,      1,   2
+",
+            "\
+client,asset,available,held,total,locked,state
+1,,2,1,3,false,active
+",
+        )
+    }
+
+    #[test]
+    fn withdrawal_dispute_and_resolve() {
+        assert_result(
+            "\
+type,    client,  tx,  amount
+deposit,      1,   1,     3.0
+withdrawal,   1,   2,     1.0
+dispute,      1,   2
+resolve,      1,   2
+",
+            "\
+client,asset,available,held,total,locked,state
+1,,2,0,2,false,active
+",
+        )
+    }
+
+    #[test]
+    fn withdrawal_dispute_and_chargeback() {
+        assert_result(
+            "\
+type,    client,  tx,  amount
+deposit,      1,   1,     3.0
+withdrawal,   1,   2,     1.0
+dispute,      1,   2
+chargeback,   1,   2
+",
+            "\
+client,asset,available,held,total,locked,state
+1,,3,0,3,true,locked
 ",
         )
     }
@@ -124,8 +532,8 @@ dispute,      1,   1
 dispute,      1,   1
 ",
             "\
-client,available,held,total,locked
-1,3,1,4,false
+client,asset,available,held,total,locked,state
+1,,3,1,4,false,active
 ",
         )
     }
@@ -142,8 +550,8 @@ chargeback,   1,   1
 chargeback,   1,   1
 ",
             "\
-client,available,held,total,locked
-1,3,0,3,true
+client,asset,available,held,total,locked,state
+1,,3,0,3,true,locked
 ",
         )
     }
@@ -161,20 +569,1079 @@ dispute,      1,   1
 chargeback,   1,   1
 ",
             "\
-client,available,held,total,locked
-1,3,0,3,true
+client,asset,available,held,total,locked,state
+1,,3,0,3,true,locked
 ",
         )
     }
 
-    fn assert_result(input: &'static str, output: &'static str) {
+    #[test]
+    fn duplicate_transaction_ids_are_ignored() {
+        assert_result(
+            "\
+type,    client,  tx,  amount
+deposit,      1,   1,     1.0
+deposit,      2,   1,     2.0
+deposit,      1,   1,     3.0
+withdrawal,   2,   1,     1.0",
+            "\
+client,asset,available,held,total,locked,state
+1,,1,0,1,false,active
+2,,0,0,0,false,active
+",
+        )
+    }
+
+    #[test]
+    fn disputes_of_other_clients_transactions_are_ignored() {
+        assert_result(
+            "\
+type,    client,  tx,  amount
+deposit,      1,   1,     1.0
+deposit,      2,   2,     2.0
+dispute,      2,   1
+chargeback,   2,   1
+",
+            "\
+client,asset,available,held,total,locked,state
+1,,1,0,1,false,active
+2,,2,0,2,false,active
+",
+        )
+    }
+
+    #[test]
+    fn rejection_reasons() {
+        use engine::RejectionReason::*;
+        assert_outcomes(
+            "\
+type,    client,  tx,  amount
+deposit,      1,   1,     1.0
+deposit,      1,   2
+withdrawal,   1,   3,     2.0
+dispute,      1,   3
+dispute,      1,   4
+resolve,      1,   1
+dispute,      1,   1
+dispute,      1,   1
+chargeback,   1,   1
+deposit,      1,   5,     1.0
+",
+            vec![
+                Ok(()),
+                Err(MissingAmount),
+                Err(InsufficientFunds),
+                Err(NotDisputable),
+                Err(UnknownTransaction),
+                Err(InvalidDisputeState),
+                Ok(()),
+                Err(InvalidDisputeState),
+                Ok(()),
+                Err(AccountLocked),
+            ],
+        )
+    }
+
+    #[test]
+    fn amounts_are_validated() {
+        use engine::RejectionReason::*;
+        assert_outcomes(
+            "\
+type,       client,  tx,  amount
+deposit,         1,   1,     -1.0
+deposit,         1,   1,      0.0
+withdrawal,      1,   1,   0.00001
+deposit,         1,   1,   1.00010
+dispute,         1,   1,     -0.5
+dispute,         1,   1,      0.5
+resolve,         1,   1,      0.5
+resolve,         1,   1
+freeze,          1,   2,      1.0
+transfer,        1,   3
+",
+            vec![
+                Err(AmountNotPositive),
+                Err(AmountNotPositive),
+                Err(AmountTooPrecise),
+                Ok(()),
+                Err(AmountNotPositive),
+                Ok(()),
+                Err(UnexpectedAmount),
+                Ok(()),
+                Err(UnexpectedAmount),
+                Err(MissingAmount),
+            ],
+        )
+    }
+
+    #[test]
+    fn overflowing_balances_are_rejected() {
+        use engine::RejectionReason::*;
+        assert_outcomes(
+            "\
+type,       client,  tx,  amount
+deposit,         1,   1,  79228162514264337593543950335
+deposit,         1,   2,  1
+withdrawal,      1,   3,  1
+deposit,         1,   4,  1
+dispute,         1,   3
+dispute,         1,   1
+deposit,         2,   5,  79228162514264337593543950335
+withdrawal,      2,   6,  1
+dispute,         2,   5
+dispute,         2,   6
+",
+            vec![
+                Ok(()),
+                Err(Overflow),
+                Ok(()),
+                Ok(()),
+                Err(Overflow),
+                Ok(()),
+                Ok(()),
+                Ok(()),
+                Ok(()),
+                Err(Overflow),
+            ],
+        );
+        let (output, _) = run_csv(
+            "\
+type,       client,  tx,  amount,                         counterparty
+deposit,         1,   1,  79228162514264337593543950335
+deposit,         2,   2,  1
+transfer,        2,   3,  1,                              1
+",
+        );
+        assert_eq!(
+            output,
+            "\
+client,asset,available,held,total,locked,state
+1,,79228162514264337593543950335,0,79228162514264337593543950335,false,active
+2,,1,0,1,false,active
+"
+        );
+    }
+
+    #[test]
+    fn policy_limits_disputes() {
+        let policy: Policy = toml::from_str(
+            "
+            disputable = [\"deposit\"]
+            allow_negative_available = false
+            max_redisputes = 1
+            lock_on_chargeback = false
+            ",
+        )
+        .unwrap();
+        let (output, rejected) = run_csv_with(
+            policy,
+            Mode::Strict,
+            "\
+type,    client,  tx,  amount
+deposit,      1,   1,     1.0
+deposit,      1,   2,     2.0
+withdrawal,   1,   3,     1.5
+dispute,      1,   2
+dispute,      1,   1
+resolve,      1,   1
+dispute,      1,   1
+resolve,      1,   1
+dispute,      1,   1
+chargeback,   1,   1
+deposit,      1,   4,     1.0
+dispute,      1,   4
+chargeback,   1,   4
+deposit,      1,   5,     0.5
+dispute,      1,   3
+",
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            "\
+client,asset,available,held,total,locked,state
+1,,2,0,2,false,active
+"
+        );
+        assert_eq!(
+            rejected,
+            "\
+type,client,tx,amount,line,reason
+dispute,1,2,,5,insufficient funds
+dispute,1,1,,10,too many disputes
+chargeback,1,1,,11,invalid dispute state
+dispute,1,3,,16,transaction not disputable
+"
+        );
+    }
+
+    #[test]
+    fn workers_produce_the_same_results() {
+        let mut input = String::from("type,client,tx,amount,asset,counterparty\n");
+        for tx in 1..1000 {
+            let client = tx % 17;
+            input += &match tx % 7 {
+                0 => format!("withdrawal,{},{},{}.5\n", client, tx, tx % 5),
+                1 => format!("dispute,{},{}\n", client, tx - 8),
+                2 => format!("resolve,{},{}\n", client, tx - 9),
+                3 => format!("chargeback,{},{}\n", client, tx - 27),
+                4 => format!("deposit,{},{},invalid\n", client, tx),
+                5 => format!("transfer,{},{},{}.5,,{}\n", client, tx, tx % 3, tx * 7 % 17),
+                _ => format!("deposit,{},{},{}.25\n", client, tx, tx % 11),
+            };
+        }
+        let sequential = run_csv_on(1, Policy::default(), Mode::Lenient, &input).unwrap();
+        let sharded = run_csv_on(4, Policy::default(), Mode::Lenient, &input).unwrap();
+        assert!(sequential.1.lines().count() > 100);
+        assert_eq!(sharded, sequential);
+    }
+
+    #[test]
+    fn disputes_outside_the_window_are_expired() {
+        let policy = Policy {
+            dispute_window: Some(2),
+            ..Policy::default()
+        };
+        let (output, rejected) = run_csv_with(
+            policy,
+            Mode::Strict,
+            "\
+type,    client,  tx,  amount
+deposit,      1,   1,     1.0
+dispute,      1,   1
+deposit,      1,   2,     2.0
+deposit,      1,   3,     3.0
+deposit,      1,   4,     4.0
+resolve,      1,   1
+dispute,      1,   1
+dispute,      1,   2
+dispute,      1,   3
+dispute,      1,   5
+",
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            "\
+client,asset,available,held,total,locked,state
+1,,7,3,10,false,active
+"
+        );
+        assert_eq!(
+            rejected,
+            "\
+type,client,tx,amount,line,reason
+dispute,1,1,,8,transaction expired
+dispute,1,2,,9,transaction expired
+dispute,1,5,,11,unknown transaction
+"
+        );
+    }
+
+    #[test]
+    fn transfers_take_no_slots_in_the_dispute_window() {
+        let policy = Policy {
+            dispute_window: Some(2),
+            ..Policy::default()
+        };
+        let (output, rejected) = run_csv_with(
+            policy,
+            Mode::Strict,
+            "\
+type,       client,  tx,  amount,  asset,  counterparty
+deposit,         1,   1,     2.0
+transfer,        1,   2,     1.0,       ,             2
+deposit,         1,   3,     1.0
+dispute,         1,   1
+dispute,         2,   2
+",
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            "\
+client,asset,available,held,total,locked,state
+1,,0,2,2,false,active
+2,,1,0,1,false,active
+"
+        );
+        assert_eq!(
+            rejected,
+            "\
+type,client,tx,amount,asset,counterparty,line,reason
+dispute,2,2,,,,6,transaction belongs to client 1
+"
+        );
+    }
+
+    #[test]
+    fn disputes_after_the_deadline_are_rejected() {
+        let policy = Policy {
+            dispute_deadline_days: Some(120),
+            ..Policy::default()
+        };
+        let (output, rejected) = run_csv_with(
+            policy,
+            Mode::Strict,
+            "\
+type,    client,  tx,  amount,  timestamp
+deposit,      1,   1,     1.0,          0
+deposit,      1,   2,     2.0,    1000000
+deposit,      1,   3,     4.0,     999999
+dispute,      1,   1,        ,   10368001
+dispute,      1,   2,        ,   10368001
+deposit,      1,   4,     8.0
+dispute,      1,   1
+",
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            "\
+client,asset,available,held,total,locked,state
+1,,9,2,11,false,active
+"
+        );
+        assert_eq!(
+            rejected,
+            "\
+type,client,tx,amount,timestamp,line,reason
+deposit,1,3,4.0,999999,4,timestamp before previous transaction
+dispute,1,1,,10368001,5,dispute deadline exceeded
+dispute,1,1,,,8,dispute deadline exceeded
+"
+        );
+    }
+
+    #[test]
+    fn balances_are_kept_per_asset() {
+        let (output, rejected) = run_csv(
+            "\
+type,       client,  tx,  amount,  asset
+deposit,         1,   1,     1.0,  BTC
+deposit,         1,   2,     5.0,  USD
+deposit,         1,   3,     2.0
+withdrawal,      1,   4,     2.0,  BTC
+withdrawal,      1,   5,     2.0,  USD
+dispute,         1,   1,        ,  USD
+dispute,         1,   1,        ,  BTC
+deposit,         2,   6,     3.0,  USD
+freeze,          3,   7,        ,
+unlock,          3,   8,        ,
+deposit,         3,   9,     5.0,  USD
+",
+        );
+        assert_eq!(
+            output,
+            "\
+client,asset,available,held,total,locked,state
+1,,2,0,2,false,active
+1,BTC,0,1,1,false,active
+1,USD,3,0,3,false,active
+2,USD,3,0,3,false,active
+3,USD,5,0,5,false,active
+"
+        );
+        assert_eq!(
+            rejected,
+            "\
+type,client,tx,amount,asset,line,reason
+withdrawal,1,4,2.0,BTC,5,insufficient funds
+dispute,1,1,,USD,7,asset mismatch
+"
+        );
+    }
+
+    #[test]
+    fn transfers_move_funds_between_clients() {
+        let (output, rejected) = run_csv(
+            "\
+type,       client,  tx,  amount,  asset,  counterparty
+deposit,         1,   1,     5.0
+deposit,         1,   2,     3.0,    USD
+deposit,         3,   3,     1.0
+dispute,         3,   3
+chargeback,      3,   3
+transfer,        1,   4,     2.0,       ,             2
+transfer,        1,   5,     4.0,       ,             2
+transfer,        1,   6,     1.0,    USD,            2
+transfer,        1,   7,     1.0,       ,             3
+transfer,        1,   8,     1.0,       ,             1
+transfer,        1,   9,     1.0
+transfer,        2,  10,     1.0,       ,             4
+dispute,         1,   4
+deposit,         1,  11,     1.0,       ,             2
+",
+        );
+        assert_eq!(
+            output,
+            "\
+client,asset,available,held,total,locked,state
+1,,3,0,3,false,active
+1,USD,2,0,2,false,active
+2,,1,0,1,false,active
+2,USD,1,0,1,false,active
+3,,0,0,0,true,locked
+4,,1,0,1,false,active
+"
+        );
+        assert_eq!(
+            rejected,
+            "\
+type,client,tx,amount,asset,counterparty,line,reason
+transfer,1,5,4.0,,2,8,insufficient funds
+transfer,1,7,1.0,,3,10,counterparty account unavailable
+transfer,1,8,1.0,,1,11,invalid counterparty
+transfer,1,9,1.0,,,12,invalid counterparty
+dispute,1,4,,,,14,transaction not disputable
+deposit,1,11,1.0,,2,15,unexpected counterparty
+"
+        );
+    }
+
+    #[test]
+    fn balance_changes_are_posted_to_the_ledger() {
+        let input = "\
+type,       client,  tx,  amount,  counterparty
+deposit,         1,   1,    10.0
+withdrawal,      1,   2,     3.0
+dispute,         1,   2,
+chargeback,      1,   2,
+deposit,         2,   3,     5.0
+transfer,        2,   4,     1.5,             3
+dispute,         2,   3,     2.0
+resolve,         2,   3,
+";
+        let engine = run(
+            Engine::new(Policy::default()),
+            csv_input(input),
+            Output::new(Format::Csv, io::sink()),
+            Output::new(Format::Csv, io::sink()),
+            Mode::Strict,
+            1,
+        )
+        .unwrap();
+        let mut postings = Vec::new();
+        write_postings(&engine, Output::new(Format::Csv, &mut postings)).unwrap();
+        assert_eq!(
+            String::from_utf8(postings).unwrap(),
+            "\
+tx,asset,debit,credit,amount
+1,,house:cash,available:1,10
+2,,available:1,house:cash,3
+2,,house:chargeback_losses,held:1,3
+2,,held:1,available:1,3
+3,,house:cash,available:2,5
+4,,available:2,available:3,1.5
+3,,available:2,held:2,2
+3,,held:2,available:2,2
+4,,available:2,available:3,1.5
+"
+        );
+        let totals: Vec<_> = engine
+            .accounts()
+            .flat_map(Account::infos)
+            .map(|info| info.total.to_string())
+            .collect();
+        assert_eq!(totals, vec!["10", "3.5", "1.5"]);
+    }
+
+    #[test]
+    fn postings_are_evicted_with_the_dispute_window() {
+        let policy = Policy {
+            dispute_window: Some(1),
+            ..Policy::default()
+        };
+        let input = "\
+type,       client,  tx,  amount
+deposit,         1,   1,     1.0
+deposit,         1,   2,     2.0
+dispute,         1,   2,
+deposit,         1,   3,     3.0
+resolve,         1,   2,
+";
+        let mut engine = run(
+            Engine::new(policy),
+            csv_input(input),
+            Output::new(Format::Csv, io::sink()),
+            Output::new(Format::Csv, io::sink()),
+            Mode::Strict,
+            1,
+        )
+        .unwrap();
+        let mut postings = Vec::new();
+        write_postings(&engine, Output::new(Format::Csv, &mut postings)).unwrap();
+        assert_eq!(
+            String::from_utf8(postings).unwrap(),
+            "\
+tx,asset,debit,credit,amount
+3,,house:cash,available:1,3
+2,,held:1,available:1,2
+"
+        );
+        // The evicted postings still count when checking the accounts, such as after loading a snapshot
+        engine.enable_checks().unwrap();
+    }
+
+    #[test]
+    fn fees_are_charged_and_credited_to_the_house() {
+        let policy: Policy = toml::from_str(
+            r#"
+[fees.withdrawal]
+percent = "1"
+flat = "0.5"
+
+[fees.chargeback]
+flat = "2"
+
+[tiers.premium]
+clients = [2]
+
+[tiers.premium.fees.withdrawal]
+percent = "0.5"
+"#,
+        )
+        .unwrap();
+        policy.validate().unwrap();
+        let input = "\
+type,       client,  tx,  amount,  asset
+deposit,         1,   1,   100.0
+withdrawal,      1,   2,    10.0
+withdrawal,      1,   3,    89.0
+deposit,         2,   4,    50.0
+withdrawal,      2,   5,    20.0
+deposit,         2,   6,    10.0,  USD
+dispute,         2,   6
+chargeback,      2,   6
+";
+        for workers in [1, 3] {
+            let mut output = Vec::new();
+            let mut rejected = Vec::new();
+            let mut fees = Vec::new();
+            let engine = run(
+                Engine::new(policy.clone()),
+                csv_input(input),
+                Output::new(Format::Csv, &mut output),
+                Output::new(Format::Csv, &mut rejected),
+                Mode::Strict,
+                workers,
+            )
+            .unwrap();
+            write_fees(&engine, Output::new(Format::Csv, &mut fees)).unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "\
+client,asset,available,held,total,locked,state
+1,,89.4,0,89.4,false,active
+2,,29.9,0,29.9,true,locked
+2,USD,-2,0,-2,true,locked
+"
+            );
+            assert_eq!(
+                String::from_utf8(rejected).unwrap(),
+                "\
+type,client,tx,amount,asset,line,reason
+withdrawal,1,3,89.0,,4,insufficient funds
+"
+            );
+            assert_eq!(
+                String::from_utf8(fees).unwrap(),
+                "\
+client,asset,fees
+1,,0.6
+2,,0.1
+2,USD,2
+,,0.7
+,USD,2
+"
+            );
+        }
+    }
+
+    #[test]
+    fn operators_change_the_account_state() {
+        let (output, rejected) = run_csv(
+            "\
+type,       client,  tx,  amount,  counterparty
+deposit,         1,   1,     5.0
+dispute,         1,   1
+chargeback,      1,   1
+deposit,         1,   2,     1.0
+freeze,          1,   3
+unlock,          1,   4
+deposit,         1,   5,     2.0
+deposit,         2,   6,     3.0
+freeze,          2,   7
+withdrawal,      2,   8,     1.0
+transfer,        1,   9,     1.0,             2
+freeze,          2,  10
+unlock,          1,  11
+close,           1,  12
+withdrawal,      1,  13,     1.0
+close,           1,  14
+deposit,         1,  15,     1.0
+unlock,          2,  16
+transfer,        2,  17,     1.0,             1
+",
+        );
+        assert_eq!(
+            output,
+            "\
+client,asset,available,held,total,locked,state
+1,,0,0,0,false,closed
+2,,4,0,4,false,active
+"
+        );
+        assert_eq!(
+            rejected,
+            "\
+type,client,tx,amount,counterparty,line,reason
+deposit,1,2,1.0,,5,account locked
+freeze,1,3,,,6,account locked
+withdrawal,2,8,1.0,,11,account frozen
+freeze,2,10,,,13,invalid account state
+unlock,1,11,,,14,invalid account state
+close,1,12,,,15,account not empty
+deposit,1,15,1.0,,18,account closed
+transfer,2,17,1.0,1,20,counterparty account unavailable
+"
+        );
+    }
+
+    #[test]
+    fn partial_disputes() {
+        let (output, rejected) = run_csv(
+            "\
+type,       client,  tx,  amount
+deposit,         1,   1,   100.0
+dispute,         1,   1,    30.0
+dispute,         1,   1,    50.0
+dispute,         1,   1,    30.0
+resolve,         1,   1
+dispute,         1,   1,    40.0
+dispute,         1,   1
+deposit,         2,   2,    10.0
+dispute,         2,   2,     4.0
+chargeback,      2,   2
+unlock,          2,   3
+dispute,         2,   2,     7.0
+dispute,         2,   2
+",
+        );
+        assert_eq!(
+            output,
+            "\
+client,asset,available,held,total,locked,state
+1,,0,100,100,false,active
+2,,0,6,6,false,active
+"
+        );
+        assert_eq!(
+            rejected,
+            "\
+type,client,tx,amount,line,reason
+dispute,1,1,30.0,5,disputed amount too large
+dispute,2,2,7.0,13,disputed amount too large
+"
+        );
+    }
+
+    #[test]
+    fn checks_detect_inconsistent_accounts() {
+        let engine = run(
+            Engine::new(Policy::default()),
+            csv_input("type,client,tx,amount\ndeposit,1,1,1.5\n"),
+            Output::new(Format::Csv, io::sink()),
+            Output::new(Format::Csv, io::sink()),
+            Mode::Strict,
+            1,
+        )
+        .unwrap();
         let mut bytes = Vec::new();
-        let reader = csv::ReaderBuilder::new()
+        snapshot::write(&engine, &mut bytes).unwrap();
+        let snapshot = String::from_utf8(bytes).unwrap();
+        let check = |balance: &str| {
+            let snapshot = snapshot.replace(r#""available":"1.5","held":"0","total":"1.5""#, balance);
+            let mut engine = snapshot::read(snapshot.as_bytes(), Policy::default()).unwrap();
+            engine.enable_checks().unwrap_err().to_string()
+        };
+
+        let error = check(r#""available":"1.5","held":"0","total":"2.5""#);
+        assert!(error.starts_with("available and held funds don't add up"), "{}", error);
+        assert!(error.contains(r#"account: {"client":1"#), "{}", error);
+        let error = check(r#""available":"1","held":"0.5","total":"1.5""#);
+        assert!(
+            error.starts_with("held funds differ from the open disputes"),
+            "{}",
+            error
+        );
+        let error = check(r#""available":"2.5","held":"0","total":"2.5""#);
+        assert_eq!(
+            error,
+            r#"total funds of all clients 2.5 differ from the 1.5 paid to them in asset ''"#
+        );
+    }
+
+    #[test]
+    fn disputes_of_transactions_from_a_snapshot() {
+        let mut bytes = Vec::new();
+        let engine = run(
+            Engine::new(Policy::default()),
+            csv_input(
+                "\
+type,    client,  tx,  amount
+deposit,      1,   1,     1.0
+deposit,      2,   2,     2.0
+dispute,      2,   2
+",
+            ),
+            Output::new(Format::Csv, io::sink()),
+            Output::new(Format::Csv, io::sink()),
+            Mode::Strict,
+            1,
+        )
+        .unwrap();
+        snapshot::write(&engine, &mut bytes).unwrap();
+        // The claimed ids are kept as ranges
+        assert!(String::from_utf8_lossy(&bytes).contains(r#""claimed":[[1,2]]"#));
+
+        let mut output = Vec::new();
+        run(
+            snapshot::read(bytes.as_slice(), Policy::default()).unwrap(),
+            csv_input(
+                "\
+type,    client,  tx,  amount
+dispute,      1,   1
+chargeback,   2,   2
+deposit,      3,   1,     1.0
+",
+            ),
+            Output::new(Format::Csv, &mut output),
+            Output::new(Format::Csv, io::sink()),
+            Mode::Strict,
+            1,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+client,asset,available,held,total,locked,state
+1,,0,1,1,false,active
+2,,0,0,0,true,locked
+3,,0,0,0,false,active
+"
+        );
+    }
+
+    #[test]
+    fn replay_journal() {
+        let path = env::temp_dir().join(format!("trading_engine_{}.journal", process::id()));
+        let mut engine = Engine::new(Policy::default());
+        engine.set_journal(Box::new(journal::open(&path).unwrap()));
+        let mut output = Vec::new();
+        run(
+            engine,
+            csv_input(
+                "\
+type,    client,  tx,  amount
+deposit,      1,   1,     1.0
+deposit,      2,   2,     2.0
+withdrawal,   2,   3,     3.0
+dispute,      2,   2
+deposit,      3,   2,     1.0
+",
+            ),
+            Output::new(Format::Csv, &mut output),
+            Output::new(Format::Csv, io::sink()),
+            Mode::Strict,
+            1,
+        )
+        .unwrap();
+        // A crash while writing leaves an incomplete entry behind
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"transaction\":{\"type\":\"dep")
+            .unwrap();
+
+        let mut engine = Engine::new(Policy::default());
+        journal::load(&mut engine, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut replayed = Vec::new();
+        write_accounts(&engine, Output::new(Format::Csv, &mut replayed)).unwrap();
+        assert_eq!(replayed, output);
+
+        let outcome = engine.handle(
+            csv_reader("type,client,tx\nresolve,2,2")
+                .deserialize()
+                .next()
+                .unwrap()
+                .unwrap(),
+        );
+        assert_eq!(outcome.unwrap(), Ok(()));
+    }
+
+    fn assert_outcomes(input: &'static str, outcomes: Vec<Outcome>) {
+        let mut reader = csv_reader(input);
+        let mut engine = Engine::new(Policy::default());
+        let actual: Vec<Outcome> = reader
+            .deserialize()
+            .map(|transaction| engine.handle(transaction.unwrap()).unwrap())
+            .collect();
+        assert_eq!(actual, outcomes);
+    }
+
+    #[test]
+    fn rejected_transactions_are_reported() {
+        let (_, rejected) = run_csv(
+            "\
+type,    client,  tx,  amount
+deposit,      1,   1,     1.0
+withdrawal,   1,   2,     2.0
+dispute,      2,   1
+deposit,      2,   1,     3.0
+",
+        );
+        assert_eq!(
+            rejected,
+            "\
+type,client,tx,amount,line,reason
+withdrawal,1,2,2.0,3,insufficient funds
+dispute,2,1,,4,transaction belongs to client 1
+deposit,2,1,3.0,5,duplicate transaction id
+"
+        );
+    }
+
+    #[test]
+    fn invalid_rows_are_skipped_in_lenient_mode() {
+        let (output, rejected) = run_csv_in(
+            Mode::Lenient,
+            "\
+type,    client,  tx,  amount
+deposit,      1,   1,     1.0
+refund,       1,   2,     1.0
+deposit,      1,   3,     abc
+deposit,  70000,   4,     1.0
+deposit,      1,   5,     2.0
+",
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            "\
+client,asset,available,held,total,locked,state
+1,,3,0,3,false,active
+"
+        );
+        let rows: Vec<csv::StringRecord> = csv::Reader::from_reader(rejected.as_bytes())
+            .records()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let lines: Vec<&str> = rows.iter().map(|row| &row[4]).collect();
+        assert_eq!(lines, vec!["3", "4", "5"]);
+        assert!(rows[0][5].contains("unknown variant `refund`"), "{}", &rows[0][5]);
+        assert!(rows[1][5].contains("\"abc\""), "{}", &rows[1][5]);
+        assert!(rows[2][5].contains("number too large"), "{}", &rows[2][5]);
+    }
+
+    #[test]
+    fn rows_with_invalid_utf8_are_reported() {
+        let input: &[u8] = b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,\xff\ndeposit,1,3,2.0\n";
+        let mut rejected = Vec::new();
+        run(
+            Engine::new(Policy::default()),
+            Input::new(InputFormat::Csv, input).unwrap(),
+            Output::new(Format::Csv, io::sink()),
+            Output::new(Format::Csv, &mut rejected),
+            Mode::Lenient,
+            1,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(rejected).unwrap(),
+            "\
+type,client,tx,amount,line,reason
+deposit,1,2,\u{fffd},3,invalid UTF-8 on line 3
+"
+        );
+
+        // Unlike invalid rows, failing to read the input is fatal
+        let failing = input.chain(io::Read::chain(&b"deposit,1,4,1.0\n"[..], FailingReader));
+        let error = run(
+            Engine::new(Policy::default()),
+            Input::new(InputFormat::Csv, failing).unwrap(),
+            Output::new(Format::Csv, io::sink()),
+            Output::new(Format::Csv, io::sink()),
+            Mode::Lenient,
+            1,
+        )
+        .err()
+        .unwrap();
+        assert!(error.to_string().contains("disconnected"), "{}", error);
+    }
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("disconnected"))
+        }
+    }
+
+    #[test]
+    fn ndjson_input_and_output() {
+        let input = r#"{"type":"deposit","client":1,"tx":1,"amount":"1.5"}
+{"type":"withdrawal","client":1,"tx":2,"amount":"2.0"}
+
+{"type":"deposit","client":2,"tx":3,"amount":12345678901234.5678}
+{"type":"deposit","client":2,"tx":4,"amount":true}
+not json
+{"type":"dispute","client":1,"tx":1}
+"#;
+        let run_ndjson = |mode| {
+            let mut output = Vec::new();
+            let mut rejected = Vec::new();
+            run(
+                Engine::new(Policy::default()),
+                Input::new(InputFormat::Ndjson, input.as_bytes())?,
+                Output::new(Format::Ndjson, &mut output),
+                Output::new(Format::Ndjson, &mut rejected),
+                mode,
+                1,
+            )?;
+            anyhow::Ok((String::from_utf8(output)?, String::from_utf8(rejected)?))
+        };
+        let (output, rejected) = run_ndjson(Mode::Lenient).unwrap();
+        assert_eq!(
+            output,
+            r#"{"client":1,"asset":"","available":"0","held":"1.5","total":"1.5","locked":false,"state":"active"}
+{"client":2,"asset":"","available":"12345678901234.5678","held":"0","total":"12345678901234.5678","locked":false,"state":"active"}
+"#
+        );
+        let rejected: Vec<serde_json::Value> = rejected
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(rejected.len(), 3);
+        assert_eq!(rejected[0]["line"], 2);
+        assert_eq!(rejected[0]["reason"], "insufficient funds");
+        assert_eq!(rejected[0]["row"]["type"], "withdrawal");
+        assert_eq!(rejected[1]["line"], 5);
+        assert!(rejected[1]["reason"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid JSON on line 5"));
+        assert_eq!(rejected[2]["line"], 6);
+        assert_eq!(rejected[2]["row"], "not json");
+
+        let error = run_ndjson(Mode::Strict).unwrap_err();
+        assert!(error.to_string().contains("line 5"), "{}", error);
+    }
+
+    #[test]
+    fn binary_input_matches_csv_input() {
+        let input = "\
+type,       client,  tx,  amount,  timestamp,  counterparty
+deposit,         1,   1,  1.2345,          1,
+deposit,         2,   2,       3,          2,
+withdrawal,      1,   3,      5,           3,
+transfer,        2,   4,    0.5,           4,            1
+dispute,         1,   1,        ,          5,
+";
+        let mut converted = Vec::new();
+        assert_eq!(binary::convert(csv_input(input), &mut converted).unwrap(), 5);
+
+        let mut output = Vec::new();
+        let mut rejected = Vec::new();
+        run(
+            Engine::new(Policy::default()),
+            Input::new(InputFormat::Binary, converted.as_slice()).unwrap(),
+            Output::new(Format::Csv, &mut output),
+            Output::new(InputFormat::Binary.report_format(), &mut rejected),
+            Mode::Strict,
+            1,
+        )
+        .unwrap();
+        let expected = run_csv_on(1, Policy::default(), Mode::Strict, input).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), expected.0);
+        assert_eq!(
+            String::from_utf8(rejected).unwrap(),
+            "\
+type,client,tx,amount,timestamp,counterparty,line,reason
+withdrawal,1,3,5,3,,3,insufficient funds
+"
+        );
+
+        let truncated = &converted[..converted.len() - 1];
+        let mut input = Input::new(InputFormat::Binary, truncated).unwrap();
+        let error = std::iter::from_fn(|| input.next().transpose())
+            .find_map(Result::err)
+            .unwrap();
+        assert_eq!(error.to_string(), "truncated record 5");
+        assert!(Input::new(InputFormat::Binary, &b"type"[..]).is_err());
+        assert!(binary::convert(
+            csv_input("type,client,tx,amount,timestamp,asset\ndeposit,1,1,1,,BTC\n"),
+            io::sink()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn invalid_rows_fail_with_their_position_in_strict_mode() {
+        let error = run_csv_in(
+            Mode::Strict,
+            "\
+type,    client,  tx,  amount
+deposit,      1,   1,     1.0
+deposit,      1,   3,     abc
+",
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("line: 3"), "{}", error);
+    }
+
+    fn csv_input(input: &str) -> Input<&[u8]> {
+        Input::new(InputFormat::Csv, input.as_bytes()).unwrap()
+    }
+
+    fn csv_reader(input: &str) -> csv::Reader<&[u8]> {
+        csv::ReaderBuilder::new()
             .flexible(true)
             .trim(csv::Trim::All)
-            .from_reader(input.as_bytes());
-        let writer = csv::Writer::from_writer(&mut bytes);
-        run(reader, writer).unwrap();
-        assert_eq!(str::from_utf8(&bytes).unwrap(), output);
+            .from_reader(input.as_bytes())
+    }
+
+    fn assert_result(input: &'static str, output: &'static str) {
+        assert_eq!(run_csv(input).0, output);
+    }
+
+    fn run_csv(input: &'static str) -> (String, String) {
+        run_csv_in(Mode::Strict, input).unwrap()
+    }
+
+    fn run_csv_in(mode: Mode, input: &str) -> Result<(String, String)> {
+        run_csv_with(Policy::default(), mode, input)
+    }
+
+    fn run_csv_with(policy: Policy, mode: Mode, input: &str) -> Result<(String, String)> {
+        run_csv_on(1, policy, mode, input)
+    }
+
+    fn run_csv_on(workers: usize, policy: Policy, mode: Mode, input: &str) -> Result<(String, String)> {
+        let mut output = Vec::new();
+        let mut rejected = Vec::new();
+        let mut engine = Engine::new(policy);
+        engine.enable_checks()?;
+        run(
+            engine,
+            csv_input(input),
+            Output::new(Format::Csv, &mut output),
+            Output::new(Format::Csv, &mut rejected),
+            mode,
+            workers,
+        )?;
+        Ok((String::from_utf8(output)?, String::from_utf8(rejected)?))
     }
 }
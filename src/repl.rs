@@ -0,0 +1,197 @@
+use crate::engine::{Account, ClientId, Engine, Policy, Transaction};
+use crate::format::{Format, Output};
+use crate::server;
+use crate::snapshot;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::io::{BufRead, Write};
+use std::iter;
+
+/// Lets an operator enter transactions as CSV rows without header, like the server,
+/// and answers each with the balances of the affected accounts.
+///
+/// Besides transactions, a line can be a command:
+/// - `show <client>` reports the balances of the client in all assets
+/// - `history <client>` reports the executed transactions that the account keeps
+/// - `postings <client>` reports the postings of the ledger accounts of the client
+/// - `tx <tx>` reports an executed transaction and the state of its disputes
+/// - `undo` reverts the latest transaction, whether it was accepted or not
+pub struct Repl {
+    engine: Engine,
+    policy: Policy,
+    format: Format,
+    /// The engine at the start, from which the remaining transactions are handled again on undo.
+    /// There is none if the engine has a journal, which already recorded the transactions.
+    start: Option<Vec<u8>>,
+    /// The transactions entered since the start
+    handled: Vec<Transaction>,
+}
+
+impl Repl {
+    pub fn new(engine: Engine, policy: Policy, format: Format) -> Result<Self> {
+        let start = if engine.journaled() {
+            None
+        } else {
+            let mut start = Vec::new();
+            snapshot::write(&engine, &mut start)?;
+            Some(start)
+        };
+        Ok(Self {
+            engine,
+            policy,
+            format,
+            start,
+            handled: Vec::new(),
+        })
+    }
+
+    /// Answers the lines until the input ends, and returns the engine.
+    /// The prompt is only useful if an operator types the lines.
+    pub fn run<R: BufRead, W: Write>(mut self, input: R, mut output: W, prompt: bool) -> Result<Engine> {
+        let mut lines = input.lines();
+        loop {
+            if prompt {
+                write!(output, "> ")?;
+                output.flush()?;
+            }
+            let Some(line) = lines.next() else {
+                return Ok(self.engine);
+            };
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Err(error) = self.respond(&line, &mut output) {
+                writeln!(output, "error: {:#}", error)?;
+            }
+        }
+    }
+
+    fn respond<W: Write>(&mut self, line: &str, output: &mut W) -> Result<()> {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("show"), Some(client)) => self.report(output, self.account(client)?.infos()),
+            (Some("history"), Some(client)) => self.report(output, self.account(client)?.history()),
+            (Some("postings"), Some(client)) => self.report(output, self.account(client)?.postings()),
+            (Some("tx"), Some(tx)) => {
+                let tx = tx.parse::<u32>().context("invalid transaction id")?.into();
+                let info = self.engine.transaction(tx).context("unknown transaction")?;
+                self.report(output, [info])
+            }
+            (Some("undo"), None) => self.undo(output),
+            _ => self.handle(line, output),
+        }
+    }
+
+    fn handle<W: Write>(&mut self, line: &str, output: &mut W) -> Result<()> {
+        let record = server::parse(line)?;
+        let transaction: Transaction = record.deserialize(Some(&csv::StringRecord::from(&server::COLUMNS[..])))?;
+        match self.engine.handle(transaction.clone())? {
+            Ok(()) => writeln!(output, "ok")?,
+            Err(reason) => writeln!(output, "rejected: {}", reason)?,
+        }
+        self.handled.push(transaction);
+        self.balances(self.handled.last(), output)
+    }
+
+    /// Rebuilds the engine from the start without the latest transaction.
+    fn undo<W: Write>(&mut self, output: &mut W) -> Result<()> {
+        let start = self
+            .start
+            .as_ref()
+            .context("cannot undo transactions recorded in a journal")?;
+        let undone = self.handled.pop().context("nothing to undo")?;
+        let mut engine = snapshot::read(start.as_slice(), self.policy.clone())?;
+        if self.engine.checked() {
+            engine.enable_checks()?;
+        }
+        for transaction in &self.handled {
+            // The outcomes are the same as when the transactions were entered
+            let _ = engine.handle(transaction.clone())?;
+        }
+        self.engine = engine;
+        writeln!(output, "undone")?;
+        self.balances(Some(&undone), output)
+    }
+
+    /// Reports the balances of the client of the transaction, and of the counterparty of a transfer.
+    fn balances<W: Write>(&self, transaction: Option<&Transaction>, output: &mut W) -> Result<()> {
+        let Some(transaction) = transaction else {
+            return Ok(());
+        };
+        let clients = iter::once(transaction.client).chain(transaction.counterparty);
+        let accounts = clients.filter_map(|client| self.engine.account(client));
+        self.report(output, accounts.flat_map(Account::infos))
+    }
+
+    fn account(&self, client: &str) -> Result<&Account> {
+        let client: ClientId = client.parse::<u16>().context("invalid client")?.into();
+        self.engine.account(client).context("unknown client")
+    }
+
+    fn report<W: Write, T: Serialize>(&self, output: &mut W, rows: impl IntoIterator<Item = T>) -> Result<()> {
+        let mut report = Output::new(self.format, &mut *output);
+        for row in rows {
+            report.serialize(row)?;
+        }
+        report.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_transactions_and_commands() {
+        let input = "\
+deposit, 1, 1, 2.0
+withdrawal, 1, 2, 3.0
+transfer, 1, 3, 0.5, , , 2
+
+show 2
+history 1
+undo
+tx 3
+dispute, 1, 1
+tx 1
+show 3
+";
+        let mut output = Vec::new();
+        let engine = Repl::new(Engine::new(Policy::default()), Policy::default(), Format::Csv)
+            .unwrap()
+            .run(input.as_bytes(), &mut output, false)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+ok
+client,asset,available,held,total,locked,state
+1,,2,0,2,false,active
+rejected: insufficient funds
+client,asset,available,held,total,locked,state
+1,,2,0,2,false,active
+ok
+client,asset,available,held,total,locked,state
+1,,1.5,0,1.5,false,active
+2,,0.5,0,0.5,false,active
+client,asset,available,held,total,locked,state
+2,,0.5,0,0.5,false,active
+type,client,tx,asset,amount,counterparty,timestamp,dispute,disputes,disputed,charged_back
+deposit,1,1,,2,,,Undisputed,0,0,0
+transfer,1,3,,0.5,2,,Undisputed,0,0,0
+undone
+client,asset,available,held,total,locked,state
+1,,2,0,2,false,active
+error: unknown transaction
+ok
+client,asset,available,held,total,locked,state
+1,,0,2,2,false,active
+type,client,tx,asset,amount,counterparty,timestamp,dispute,disputes,disputed,charged_back
+deposit,1,1,,2,,,Disputed,1,2,0
+error: unknown client
+"
+        );
+        assert!(engine.account(ClientId::from(2)).is_none());
+    }
+}
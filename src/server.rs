@@ -0,0 +1,187 @@
+use crate::engine::{Asset, ClientId, Engine, Transaction};
+use anyhow::{anyhow, Context, Result};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::{task, time};
+
+/// The columns of the transaction rows, which are sent without a header
+pub const COLUMNS: [&str; 7] = ["type", "client", "tx", "amount", "timestamp", "asset", "counterparty"];
+
+/// The number of connections served at the same time. Further connections wait until one is closed.
+const MAX_CONNECTIONS: usize = 1024;
+
+/// How long to wait after failing to accept a connection, before trying again
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The longest line that is read, including the line break. A connection that sends
+/// a longer line is closed, rather than buffering however much it sends.
+const MAX_LINE_LENGTH: u64 = 1024;
+
+/// Accepts connections that stream transactions as CSV rows, one per line.
+///
+/// Every line gets a reply line:
+/// - `ok` if the transaction was handled
+/// - `rejected: <reason>` if it was rejected
+/// - `error: <message>` if it could not be parsed
+///
+/// A line `account,<client>[,<asset>]` is answered with the current balances of the client
+/// as CSV rows, in all assets it holds or only in the given asset, followed by an empty line.
+/// The lines of a connection are handled one after the other, so the transactions
+/// of a connection are handled in their order. Operator actions are rejected, as the
+/// connections come from partners. The engine is only accessed on blocking threads,
+/// as handling a transaction may wait for the journal to be written to disk.
+pub async fn serve(engine: Arc<Mutex<Engine>>, listener: TcpListener) {
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        // The semaphore is never closed
+        let Ok(permit) = connections.clone().acquire_owned().await else {
+            return;
+        };
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let engine = engine.clone();
+                tokio::spawn(async move {
+                    if let Err(error) = connection(engine, stream).await {
+                        eprintln!("Connection failed: {:#}", error);
+                    }
+                    drop(permit);
+                });
+            }
+            // Such as running out of file descriptors, which doesn't affect other connections,
+            // but would fail again right away if we didn't wait for connections to be closed
+            Err(error) => {
+                eprintln!("Failed to accept connection: {}", error);
+                time::sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+async fn connection(engine: Arc<Mutex<Engine>>, stream: TcpStream) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if (&mut reader).take(MAX_LINE_LENGTH).read_until(b'\n', &mut line).await? == 0 {
+            return Ok(());
+        }
+        if line.last() != Some(&b'\n') && line.len() as u64 == MAX_LINE_LENGTH {
+            writer.write_all(b"error: line too long\n").await?;
+            return Ok(());
+        }
+        let line = std::str::from_utf8(&line).context("line is not UTF-8")?;
+        let line = line.trim_end_matches(['\n', '\r']).to_string();
+        let engine = engine.clone();
+        let mut reply = task::spawn_blocking(move || respond(&engine, &line)).await?;
+        reply.push('\n');
+        writer.write_all(reply.as_bytes()).await?;
+    }
+}
+
+fn respond(engine: &Mutex<Engine>, line: &str) -> String {
+    match handle(engine, line) {
+        Ok(reply) => reply,
+        Err(error) => format!("error: {:#}", error),
+    }
+}
+
+fn handle(engine: &Mutex<Engine>, line: &str) -> Result<String> {
+    let record = parse(line)?;
+    if record.get(0) == Some("account") {
+        let client: ClientId = record.get(1).context("missing client")?.parse::<u16>()?.into();
+        let engine = engine.lock().map_err(|_| anyhow!("engine poisoned"))?;
+        let account = engine.account(client).context("unknown client")?;
+        let infos = match record.get(2) {
+            Some(asset) => vec![account.balance(&Asset::from(asset)).context("unknown asset")?],
+            None => account.infos(),
+        };
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+        for info in infos {
+            writer.serialize(info)?;
+        }
+        return Ok(String::from_utf8(writer.into_inner()?)?);
+    }
+    let transaction: Transaction = record.deserialize(Some(&csv::StringRecord::from(&COLUMNS[..])))?;
+    let mut engine = engine.lock().map_err(|_| anyhow!("engine poisoned"))?;
+    Ok(match engine.handle_partner(transaction)? {
+        Ok(()) => "ok".to_string(),
+        Err(reason) => format!("rejected: {}", reason),
+    })
+}
+
+pub fn parse(line: &str) -> Result<csv::StringRecord> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(line.as_bytes());
+    let mut record = csv::StringRecord::new();
+    reader.read_record(&mut record)?;
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Policy;
+
+    #[tokio::test]
+    async fn replies_to_each_line() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let engine = Arc::new(Mutex::new(Engine::new(Policy::default())));
+        tokio::spawn(serve(engine, listener));
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer
+            .write_all(b"deposit, 1, 1, 2.0\nwithdrawal, 1, 2, 3.0\ndeposit, 1\naccount, 1\naccount, 2\n")
+            .await
+            .unwrap();
+        // A partner cannot unlock its account after a chargeback
+        writer
+            .write_all(b"deposit, 2, 3, 5.0\ndispute, 2, 3\nchargeback, 2, 3\nunlock, 2, 4\naccount, 2\n")
+            .await
+            .unwrap();
+        // A client with funds in another asset only has no balance in the default asset
+        writer
+            .write_all(b"deposit, 3, 5, 1.0, , BTC\naccount, 3\naccount, 3,\n")
+            .await
+            .unwrap();
+        writer.shutdown().await.unwrap();
+        let mut lines = BufReader::new(reader).lines();
+        let mut replies = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            replies.push(line);
+        }
+        assert_eq!(replies[0], "ok");
+        assert_eq!(replies[1], "rejected: insufficient funds");
+        assert!(replies[2].starts_with("error: "), "{}", replies[2]);
+        assert_eq!(replies[3..5], ["1,,2,0,2,false,active", ""]);
+        assert_eq!(replies[5], "error: unknown client");
+        assert_eq!(replies[6..9], ["ok", "ok", "ok"]);
+        assert_eq!(replies[9], "rejected: operator action not allowed");
+        assert_eq!(replies[10..12], ["2,,0,0,0,true,locked", ""]);
+        assert_eq!(replies[12..15], ["ok", "3,BTC,1,0,1,false,active", ""]);
+        assert_eq!(replies[15], "error: unknown asset");
+    }
+
+    #[tokio::test]
+    async fn closes_connections_with_long_lines() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let engine = Arc::new(Mutex::new(Engine::new(Policy::default())));
+        tokio::spawn(serve(engine, listener));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"deposit, 1, 1, 2.0\n").await.unwrap();
+        stream.write_all(&[b' '; MAX_LINE_LENGTH as usize]).await.unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).await.unwrap();
+        assert_eq!(replies, "ok\nerror: line too long\n");
+    }
+}
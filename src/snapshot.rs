@@ -0,0 +1,55 @@
+use crate::engine::{Engine, Policy};
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Incremented whenever the format of the engine state changes
+const VERSION: u32 = 12;
+
+/// Precedes the engine state, so that the version is checked before reading the state
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+}
+
+/// Writes the full state of the engine, including the transaction history that later disputes refer to.
+pub fn write<W: Write>(engine: &Engine, mut writer: W) -> Result<()> {
+    serde_json::to_writer(&mut writer, &Header { version: VERSION })?;
+    writer.write_all(b"\n")?;
+    serde_json::to_writer(&mut writer, engine)?;
+    Ok(())
+}
+
+pub fn read<R: Read>(reader: R, policy: Policy) -> Result<Engine> {
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let header = Header::deserialize(&mut deserializer)?;
+    ensure!(
+        header.version == VERSION,
+        "unsupported snapshot version {}, expected {}",
+        header.version,
+        VERSION
+    );
+    let mut engine = Engine::deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    engine.set_policy(policy);
+    Ok(engine)
+}
+
+/// Saves the snapshot to a temporary file first, so that
+/// a crash never leaves a partially written snapshot behind.
+pub fn save(engine: &Engine, path: &Path) -> Result<()> {
+    let temporary = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temporary)?);
+    write(engine, &mut writer)?;
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+pub fn load(path: &Path, policy: Policy) -> Result<Engine> {
+    let reader = BufReader::new(File::open(path)?);
+    read(reader, policy).with_context(|| format!("failed to load snapshot {}", path.display()))
+}
@@ -0,0 +1,165 @@
+use crate::engine::{Accounts, ClientId, Engine, Outcome, Registry, RejectionReason, Transaction, TransactionType};
+use crate::format::Record;
+use anyhow::{anyhow, Context, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::BuildHasher;
+use std::hash::BuildHasherDefault;
+use std::mem;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::thread;
+use std::thread::JoinHandle;
+
+/// How many transactions can be queued for each worker
+const QUEUE_SIZE: usize = 1024;
+
+/// A transaction together with its row in the input
+pub struct Job {
+    pub record: Record,
+    pub transaction: Transaction,
+}
+
+/// A transaction that had no effect
+pub type Rejection = (Record, RejectionReason);
+
+/// A worker thread, returning its accounts and rejections when done
+type Worker = JoinHandle<Result<(Accounts, Vec<Rejection>)>>;
+
+/// The work of a worker, in the order of the input
+enum Message {
+    /// A transaction of a client of the worker, after it has been checked by the registry
+    Handle(Job, Outcome),
+    /// A transfer to a client of another worker, which first learns whether that worker
+    /// can credit the counterparty, and then tells it whether the transfer was accepted
+    Debit(Job, Outcome, Receiver<Outcome>, SyncSender<bool>),
+    /// A transfer from a client of another worker, which tells that worker whether
+    /// the counterparty can be credited, and applies the credit if the transfer was accepted
+    Credit(Transaction, SyncSender<Outcome>, Receiver<bool>),
+}
+
+/// Handles the accounts on worker threads, each owning the accounts of a shard of the clients.
+///
+/// The registry of transaction ids stays on the dispatching thread. As the transactions
+/// of a client always go to the same worker, they are handled in their original order.
+///
+/// A transfer between clients of different shards is handled by the workers of both shards
+/// together, while the other workers carry on. As every worker gets the transfers in the order
+/// of the input, the two workers always wait for each other on the same transfer.
+pub struct Workers {
+    count: usize,
+    registry: Registry,
+    senders: Vec<SyncSender<Message>>,
+    workers: Vec<Worker>,
+}
+
+impl Workers {
+    pub fn spawn(engine: Engine, count: usize) -> Workers {
+        let (registry, accounts) = engine.into_parts();
+        let (senders, workers) = accounts
+            .partition(count, |client| shard(client, count))
+            .into_iter()
+            .map(|accounts| {
+                let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+                (sender, thread::spawn(move || work(accounts, receiver)))
+            })
+            .unzip();
+        Self {
+            count,
+            registry,
+            senders,
+            workers,
+        }
+    }
+
+    pub fn handle(&mut self, job: Job) -> Result<()> {
+        let transaction = &job.transaction;
+        let client = shard(transaction.client, self.count);
+        let registered = self.registry.register(transaction);
+        let counterparty = match (transaction.transaction_type, transaction.counterparty) {
+            (TransactionType::Transfer, Some(counterparty)) => shard(counterparty, self.count),
+            _ => client,
+        };
+        if counterparty == client {
+            return self.send(client, Message::Handle(job, registered));
+        }
+        let (credit, credited) = mpsc::sync_channel(1);
+        let (accept, accepted) = mpsc::sync_channel(1);
+        self.send(counterparty, Message::Credit(transaction.clone(), credit, accepted))?;
+        self.send(client, Message::Debit(job, registered, credited, accept))
+    }
+
+    /// Waits for the workers to handle all transactions, and returns
+    /// the engine together with the rejections of the transactions.
+    pub fn finish(self) -> Result<(Engine, Vec<Rejection>)> {
+        let Self {
+            registry,
+            senders,
+            workers,
+            ..
+        } = self;
+        drop(senders);
+        let mut merged: Option<Accounts> = None;
+        let mut rejections = Vec::new();
+        for worker in workers {
+            let (accounts, rejected) = worker.join().map_err(|_| anyhow!("worker panicked"))??;
+            match &mut merged {
+                Some(merged) => merged.merge(accounts),
+                None => merged = Some(accounts),
+            }
+            rejections.extend(rejected);
+        }
+        let accounts = merged.context("no workers")?;
+        Ok((Engine::from_parts(registry, accounts), rejections))
+    }
+
+    fn send(&mut self, worker: usize, message: Message) -> Result<()> {
+        if self.senders[worker].send(message).is_err() {
+            // The worker only stops early when it failed
+            let workers = mem::take(&mut self.workers);
+            self.senders.clear();
+            for worker in workers {
+                worker.join().map_err(|_| anyhow!("worker panicked"))??;
+            }
+            return Err(anyhow!("worker stopped"));
+        }
+        Ok(())
+    }
+}
+
+fn work(mut accounts: Accounts, receiver: Receiver<Message>) -> Result<(Accounts, Vec<Rejection>)> {
+    let mut rejections = Vec::new();
+    for message in receiver {
+        let (record, outcome) = match message {
+            Message::Handle(Job { record, transaction }, registered) => {
+                (record, accounts.handle(transaction, registered, None))
+            }
+            Message::Debit(Job { record, transaction }, registered, credited, accept) => {
+                // The other worker only stops early when it failed, which stops the whole run
+                let credit = credited.recv().context("worker of the counterparty stopped")?;
+                let outcome = accounts.handle_debit(transaction, registered, credit);
+                let accepted = matches!(outcome, Ok(Ok(())));
+                accept.send(accepted).context("worker of the counterparty stopped")?;
+                (record, outcome)
+            }
+            Message::Credit(transaction, credit, accepted) => {
+                let planned = accounts.plan_credit(&transaction)?;
+                let outcome = planned.as_ref().map(|_| ()).map_err(Clone::clone);
+                credit.send(outcome).context("worker of the sender stopped")?;
+                if let (true, Ok(transition)) = (accepted.recv().context("worker of the sender stopped")?, planned) {
+                    accounts.apply_credit(&transaction, transition)?;
+                }
+                continue;
+            }
+        };
+        let outcome = outcome.with_context(|| format!("failed to handle transaction on line {}", record.line()))?;
+        if let Err(reason) = outcome {
+            rejections.push((record, reason));
+        }
+    }
+    Ok((accounts, rejections))
+}
+
+/// The shard that owns the account of the client
+fn shard(client: ClientId, count: usize) -> usize {
+    BuildHasherDefault::<DefaultHasher>::default().hash_one(client) as usize % count
+}